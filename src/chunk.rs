@@ -0,0 +1,758 @@
+use crate::{
+    compress::{self, Compressor},
+    evict::Evictor,
+    health::Health,
+    remote::{RemoteBackend, RemoteFile},
+    store::Store,
+};
+use fuser::FileAttr;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Seek},
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, Sender},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    time::SystemTime,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+pub const CHUNKS_DIR: &str = "chunks";
+/// where files are written before being renamed into place, emptied at startup
+pub const TMP_DIR: &str = "tmp";
+pub const CHUNK_SIZE: u64 = 1 << 20;
+
+/// blake3 of a file's contents
+pub type ContentHash = [u8; 32];
+
+pub fn hash(reader: impl Read) -> Result<ContentHash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(reader)?;
+    Ok(hasher.finalize().into())
+}
+
+/// which chunks of a sparse cache file have been fetched, lives at chunks/<path> while the file is
+/// incomplete and is deleted once every chunk is present
+#[derive(Serialize, Deserialize)]
+struct ChunkMap {
+    // the remote file this was made from, if these change we start over
+    size: u64,
+    mtime: SystemTime,
+    chunk_size: u64,
+    present: Vec<u64>,
+}
+
+impl ChunkMap {
+    fn new(size: u64, mtime: SystemTime, chunk_size: u64) -> Self {
+        let chunks = size.div_ceil(chunk_size);
+        ChunkMap {
+            size,
+            mtime,
+            chunk_size,
+            present: vec![0; chunks.div_ceil(64) as usize],
+        }
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        bincode::deserialize_from(file).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn save(&self, path: &Path, tmp_dir: &Path) -> Result<()> {
+        let tmp = tmp_path(tmp_dir)?;
+        {
+            let file = BufWriter::new(File::create(&tmp)?);
+            bincode::serialize_into(file, self).map_err(Error::other)?;
+        }
+        std::fs::rename(tmp, path)
+    }
+
+    fn chunks(&self) -> u64 {
+        self.size.div_ceil(self.chunk_size)
+    }
+
+    fn has(&self, chunk: u64) -> bool {
+        self.present[(chunk / 64) as usize] & (1 << (chunk % 64)) != 0
+    }
+
+    fn set(&mut self, chunk: u64) {
+        self.present[(chunk / 64) as usize] |= 1 << (chunk % 64);
+    }
+
+    fn is_complete(&self) -> bool {
+        (0..self.chunks()).all(|chunk| self.has(chunk))
+    }
+
+    fn present_bytes(&self) -> u64 {
+        (0..self.chunks())
+            .filter(|chunk| self.has(*chunk))
+            .map(|chunk| (self.size - chunk * self.chunk_size).min(self.chunk_size))
+            .sum()
+    }
+}
+
+/// a unique temporary name in tmp_dir, which is created if need be, to be renamed into place, so
+/// it has to be on the same filesystem, and never in the cache root where a remote file could have
+/// the same name
+pub fn tmp_path(tmp_dir: &Path) -> Result<PathBuf> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    std::fs::create_dir_all(tmp_dir)?;
+    Ok(tmp_dir.join(format!(
+        "cache-fs.{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )))
+}
+
+/// delete leftovers of anything interrupted by a crash: everything in the tmp dir, maps whose
+/// cache file is gone, and the single shared temporary files older versions copied through
+pub fn cleanup(cache_dir: &Path) {
+    for legacy in ["tmp.file", "prefetch.tmp.file"] {
+        std::fs::remove_file(cache_dir.join(legacy)).ok();
+    }
+    match std::fs::remove_dir_all(cache_dir.join(TMP_DIR)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            warn!("cannot clear {:?}: {:?}", cache_dir.join(TMP_DIR), e)
+        }
+        _ => (),
+    }
+    let root = cache_dir.join("root");
+    let chunks_dir = cache_dir.join(CHUNKS_DIR);
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let read_dir = match std::fs::read_dir(chunks_dir.join(&dir)) {
+            Ok(read_dir) => read_dir,
+            Err(_) => continue,
+        };
+        for de in read_dir.flatten() {
+            let path = dir.join(de.file_name());
+            if de.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                dirs.push(path);
+            } else if !root.join(&path).exists() {
+                debug!("removing orphaned {:?}", de.path());
+                std::fs::remove_file(de.path()).ok();
+            }
+        }
+    }
+}
+
+/// where the chunk map for path lives
+pub fn map_path(chunks_dir: &Path, path: &Path) -> PathBuf {
+    chunks_dir.join(path)
+}
+
+/// a file under the cache root is only complete if it has no chunk map
+pub fn is_complete(cache_dir: &Path, chunks_dir: &Path, path: &Path) -> bool {
+    cache_dir.join(path).exists() && !map_path(chunks_dir, path).exists()
+}
+
+/// what's wrong with a cached file
+#[derive(Debug)]
+pub enum Corrupt {
+    Size { expected: u64, found: u64 },
+    Hash,
+    // left over from a different version of the remote file
+    StaleMap,
+    BadMap(Error),
+}
+
+impl std::fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Corrupt::Size { expected, found } => write!(f, "size is {found} not {expected}"),
+            Corrupt::Hash => write!(f, "contents don't match the hash in the index"),
+            Corrupt::StaleMap => write!(f, "chunk map is for a different version of the file"),
+            Corrupt::BadMap(e) => write!(f, "unreadable chunk map: {e}"),
+        }
+    }
+}
+
+/// check the cached copy of path against the remote's attr, and if it's complete and given its
+/// hash, against that too, which means reading all of it, Ok(None) if it's fine or not cached at all
+///
+/// complete files get the remote's mtime, ones of the right size without it were cached before
+/// that, so they're given it if stamp rather than counting as corrupt
+pub fn verify(
+    cache_dir: &Path,
+    chunks_dir: &Path,
+    path: &Path,
+    attr: &FileAttr,
+    hash: Option<&ContentHash>,
+    stamp: bool,
+) -> Result<Option<Corrupt>> {
+    let file = match File::open(cache_dir.join(path)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let meta = file.metadata()?;
+    let size = compress::plain_size(&file)?;
+    if size != attr.size {
+        return Ok(Some(Corrupt::Size {
+            expected: attr.size,
+            found: size,
+        }));
+    }
+    match ChunkMap::load(&map_path(chunks_dir, path)) {
+        Ok(map) if map.size != attr.size || map.mtime != attr.mtime => Ok(Some(Corrupt::StaleMap)),
+        // partial, every chunk it says is present was synced before it said so
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            // a stored copy is shared by every file with the same contents whatever their mtimes,
+            // which were hashed when it was stored
+            let legacy = meta.nlink() == 1 && meta.modified()? != attr.mtime;
+            if let Some(hash) = hash {
+                if *hash != self::hash(compress::plain_reader(file.try_clone()?)?)? {
+                    return Ok(Some(Corrupt::Hash));
+                }
+            }
+            if legacy && stamp {
+                debug!(
+                    "{:?} was cached before complete files got the mtime, setting it",
+                    path
+                );
+                file.set_modified(attr.mtime)?;
+            }
+            Ok(None)
+        }
+        Err(e) => Ok(Some(Corrupt::BadMap(e))),
+    }
+}
+
+/// delete the cached copy of path and its chunk map
+pub fn discard(cache_dir: &Path, chunks_dir: &Path, path: &Path) -> Result<()> {
+    for path in [cache_dir.join(path), map_path(chunks_dir, path)] {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// how much of a file is in the cache, with the number of bytes present
+pub enum Cached {
+    None,
+    Partial(u64),
+    Full(u64),
+}
+
+pub fn cached(cache_dir: &Path, chunks_dir: &Path, path: &Path) -> Cached {
+    let file = match File::open(cache_dir.join(path)) {
+        Ok(file) => file,
+        Err(_) => return Cached::None,
+    };
+    match ChunkMap::load(&map_path(chunks_dir, path)) {
+        Ok(map) => Cached::Partial(map.present_bytes()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            Cached::Full(compress::plain_size(&file).unwrap_or(0))
+        }
+        // unreadable, so it'll be started over next time it's opened
+        Err(_) => Cached::Partial(0),
+    }
+}
+
+/// a sparse cache file that fetches chunks from the remote as they are read, and optionally in the
+/// background by a Downloader
+#[derive(Debug)]
+pub struct PartialFile {
+    file: File,
+    cache_path: PathBuf,
+    map: ChunkMap,
+    map_path: PathBuf,
+    tmp_dir: PathBuf,
+    remote: Arc<dyn RemoteBackend>,
+    // relative to the remote root
+    remote_path: PathBuf,
+    remote_file: Option<Box<dyn RemoteFile>>,
+    // checked once every chunk is present, before the file counts as complete
+    hash: Option<ContentHash>,
+    // where it goes once complete, if the cache dir has a store
+    store: Option<Store>,
+    // which stores it instead once it's compressed
+    compressor: Option<Arc<Compressor>>,
+    readahead: u64,
+    health: Arc<Health>,
+}
+
+impl std::fmt::Debug for ChunkMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ChunkMap {{ size: {}, chunks: {} }}",
+            self.size,
+            self.chunks()
+        )
+    }
+}
+
+impl PartialFile {
+    /// open or create the sparse cache file and its chunk map, if the map doesn't match attr the
+    /// remote file changed, so start over
+    #[allow(clippy::too_many_arguments)]
+    pub fn open(
+        cache_path: &Path,
+        map_path: PathBuf,
+        tmp_dir: PathBuf,
+        remote: Arc<dyn RemoteBackend>,
+        remote_path: PathBuf,
+        attr: &FileAttr,
+        hash: Option<ContentHash>,
+        readahead: u64,
+        health: Arc<Health>,
+    ) -> Result<Self> {
+        let map = match ChunkMap::load(&map_path) {
+            Ok(map) if map.size == attr.size && map.mtime == attr.mtime && cache_path.exists() => {
+                map
+            }
+            Ok(_) => {
+                debug!("remote changed, discarding chunks of {:?}", cache_path);
+                ChunkMap::new(attr.size, attr.mtime, CHUNK_SIZE)
+            }
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("bad chunk map {:?}, starting over: {:?}", map_path, e);
+                }
+                ChunkMap::new(attr.size, attr.mtime, CHUNK_SIZE)
+            }
+        };
+        if map.is_complete() {
+            // only possible for empty files
+            match std::fs::remove_file(&map_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        } else {
+            if let Some(parent) = map_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            map.save(&map_path, &tmp_dir)?;
+        }
+
+        if let Some(parent) = cache_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(cache_path)?;
+        // sparse, so this takes no space until chunks are written
+        file.set_len(attr.size)?;
+        if map.is_complete() {
+            file.set_modified(attr.mtime)?;
+        }
+
+        Ok(PartialFile {
+            file,
+            cache_path: cache_path.to_path_buf(),
+            map,
+            map_path,
+            tmp_dir,
+            remote,
+            remote_path,
+            remote_file: None,
+            hash,
+            store: None,
+            compressor: None,
+            readahead,
+            health,
+        })
+    }
+
+    /// read from the cache file, fetching any missing chunks (plus readahead) first
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if offset >= self.map.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let end = (offset + buf.len() as u64).min(self.map.size);
+        let first = offset / self.map.chunk_size;
+        let last = (end - 1) / self.map.chunk_size;
+        if (first..=last).any(|chunk| !self.map.has(chunk)) {
+            let readahead = self.readahead.div_ceil(self.map.chunk_size);
+            let last = (last + readahead).min(self.map.chunks() - 1);
+            self.fetch(first, last)?;
+        }
+        self.file.read_at(buf, offset)
+    }
+
+    pub fn cached_bytes(&self) -> u64 {
+        self.map.present_bytes()
+    }
+
+    /// fetch the next run of up to max missing chunks, returns false once every chunk is present
+    pub fn fetch_next(&mut self, max: u64) -> Result<bool> {
+        let first = match (0..self.map.chunks()).find(|chunk| !self.map.has(*chunk)) {
+            Some(first) => first,
+            None => return Ok(false),
+        };
+        let last = (first + max - 1).min(self.map.chunks() - 1);
+        self.fetch(first, last)?;
+        Ok(!self.map.is_complete())
+    }
+
+    /// fetch every missing chunk from first to last inclusive, in as few remote reads as possible
+    fn fetch(&mut self, first: u64, last: u64) -> Result<()> {
+        // rather than risk hanging on it
+        self.health.check()?;
+        // evicted, or the remote file changed, either way what's fetched would go nowhere and the
+        // chunk map would be saved for a file that isn't there
+        if self.file.metadata()?.nlink() == 0 {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "the cache file was deleted while it was being fetched",
+            ));
+        }
+        if self.remote_file.is_none() {
+            self.remote_file = Some(self.remote.open(&self.remote_path)?);
+        }
+        let remote = self.remote_file.as_ref().expect("just opened");
+
+        let mut chunk = first;
+        let mut buf = Vec::new();
+        while chunk <= last {
+            if self.map.has(chunk) {
+                chunk += 1;
+                continue;
+            }
+            let run_start = chunk;
+            while chunk <= last && !self.map.has(chunk) {
+                chunk += 1;
+            }
+            let start = run_start * self.map.chunk_size;
+            let end = (chunk * self.map.chunk_size).min(self.map.size);
+            debug!("fetching {:?} bytes {start}..{end}", self.remote_path);
+            buf.resize((end - start) as usize, 0);
+            if let Err(e) = remote.read_exact_at(&mut buf, start) {
+                // it may be what failed, like an SFTP handle on a connection that dropped, so it's
+                // opened again next time
+                self.remote_file = None;
+                return Err(e);
+            }
+            self.file.write_all_at(&buf, start)?;
+            for c in run_start..chunk {
+                self.map.set(c);
+            }
+        }
+
+        // data has to be on disk before the map says it is
+        self.file.sync_data()?;
+        if self.map.is_complete() {
+            debug!("all chunks of {:?} cached", self.remote_path);
+            self.check_hash()?;
+            // so verify can tell it's the copy of this version of the remote file
+            self.file.set_modified(self.map.mtime)?;
+            match std::fs::remove_file(&self.map_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+            self.remote_file = None;
+            match &self.compressor {
+                Some(compressor) => compressor.queue(self.cache_path.clone(), self.hash),
+                None => self.add_to_store(),
+            }
+            Ok(())
+        } else {
+            self.map.save(&self.map_path, &self.tmp_dir)
+        }
+    }
+
+    /// once every chunk is present, read it all back and compare it to the hash from the index, if
+    /// it doesn't match start over rather than let it count as complete
+    fn check_hash(&mut self) -> Result<()> {
+        let expected = match self.hash {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        let mut file = &self.file;
+        file.rewind()?;
+        if hash(file)? == expected {
+            return Ok(());
+        }
+        error!(
+            "{:?} doesn't match its hash in the index, discarding it",
+            self.remote_path
+        );
+        self.map = ChunkMap::new(self.map.size, self.map.mtime, self.map.chunk_size);
+        self.map.save(&self.map_path, &self.tmp_dir)?;
+        self.remote_file = None;
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "contents don't match the hash in the index",
+        ))
+    }
+
+    /// put the complete file in the store, hashing it first if the index has no hash for it, it's
+    /// still cached if this fails, just not deduplicated
+    fn add_to_store(&self) {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
+        };
+        let hash = match self.hash {
+            Some(hash) => Ok(hash),
+            None => {
+                let mut file = &self.file;
+                file.rewind().and_then(|_| hash(file))
+            }
+        };
+        if let Err(e) = hash.and_then(|hash| store.add(&hash, &self.cache_path)) {
+            error!("cannot store {:?}: {:?}", self.cache_path, e);
+        }
+    }
+}
+
+// how many chunks are fetched per lock by whole file downloads, reads wait at most this long
+const DOWNLOAD_CHUNKS: u64 = 4;
+// how many files the Downloader fetches at once, more queue up behind them
+const DOWNLOAD_THREADS: usize = 4;
+
+/// fetch every missing chunk of file, only holding the lock for a few chunks at a time
+pub fn download(file: &Mutex<PartialFile>) -> Result<()> {
+    loop {
+        let mut file = file.lock().expect("partial file lock poisoned");
+        if !file.fetch_next(DOWNLOAD_CHUNKS)? {
+            return Ok(());
+        }
+    }
+}
+
+/// every file being cached right now by inode, so opens, the downloader and the prefetcher all
+/// share one PartialFile and its chunk map instead of racing each other
+pub struct Downloads {
+    pub remote: Arc<dyn RemoteBackend>,
+    pub cache_dir: PathBuf,
+    pub chunks_dir: PathBuf,
+    pub tmp_dir: PathBuf,
+    pub evictor: Arc<Mutex<Evictor>>,
+    pub health: Arc<Health>,
+    pub store: Option<Store>,
+    pub compressor: Option<Arc<Compressor>>,
+    files: Mutex<HashMap<u64, Fetching>>,
+}
+
+struct Fetching {
+    file: Weak<Mutex<PartialFile>>,
+    // of its cache file, one that's been deleted since isn't joined
+    cache_ino: u64,
+}
+
+impl Downloads {
+    pub fn new(
+        remote: Arc<dyn RemoteBackend>,
+        cache_dir: PathBuf,
+        chunks_dir: PathBuf,
+        tmp_dir: PathBuf,
+        evictor: Arc<Mutex<Evictor>>,
+        health: Arc<Health>,
+        store: Option<Store>,
+    ) -> Self {
+        Downloads {
+            remote,
+            cache_dir,
+            chunks_dir,
+            tmp_dir,
+            evictor,
+            health,
+            store,
+            compressor: None,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// compress files at level once they're complete
+    pub fn compress(&mut self, level: i32) {
+        self.compressor = Some(Arc::new(Compressor::new(
+            level,
+            self.cache_dir.clone(),
+            self.chunks_dir.clone(),
+            self.tmp_dir.clone(),
+            self.evictor.clone(),
+            self.store.clone(),
+        )));
+    }
+
+    /// wait for files still being compressed
+    pub fn finish(&self) {
+        if let Some(compressor) = &self.compressor {
+            compressor.finish();
+        }
+    }
+
+    /// whether path is completely cached, if nothing of it is but the store has its contents
+    /// they're linked in and it is
+    pub fn is_complete(&self, path: &Path, hash: Option<&ContentHash>) -> bool {
+        if is_complete(&self.cache_dir, &self.chunks_dir, path) {
+            return true;
+        }
+        let (store, hash) = match (&self.store, hash) {
+            (Some(store), Some(hash)) => (store, hash),
+            _ => return false,
+        };
+        let cache_path = self.cache_dir.join(path);
+        if cache_path.exists() {
+            return false;
+        }
+        match store.link(hash, &cache_path) {
+            Ok(true) => {
+                debug!("{:?} linked in from the store", path);
+                let size = std::fs::metadata(&cache_path).map_or(0, |meta| meta.len());
+                self.evictor().add(path, size);
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                warn!("cannot link {:?} in from the store: {:?}", path, e);
+                false
+            }
+        }
+    }
+
+    /// join the download of ino if there is one, otherwise start caching it, making room for it
+    /// first if it's not in the cache at all yet
+    pub fn open(
+        &self,
+        ino: u64,
+        path: &Path,
+        attr: &FileAttr,
+        hash: Option<ContentHash>,
+        readahead: u64,
+    ) -> Result<Arc<Mutex<PartialFile>>> {
+        let mut files = self.files.lock().expect("downloads lock poisoned");
+        let cache_path = self.cache_dir.join(path);
+        if let Some(fetching) = files.get(&ino) {
+            let current =
+                std::fs::metadata(&cache_path).is_ok_and(|meta| meta.ino() == fetching.cache_ino);
+            if let Some(file) = fetching.file.upgrade().filter(|_| current) {
+                return Ok(file);
+            }
+        }
+        files.retain(|_, fetching| fetching.file.strong_count() > 0);
+
+        if cache_path.exists() {
+            self.evictor().touch(path);
+        } else {
+            // nothing of it is cached, so there'd be nothing to read
+            self.health.check()?;
+            self.evictor().make_room(attr.size);
+            self.evictor().add(path, attr.size);
+        }
+        let mut file = PartialFile::open(
+            &cache_path,
+            map_path(&self.chunks_dir, path),
+            self.tmp_dir.clone(),
+            self.remote.clone(),
+            path.to_path_buf(),
+            attr,
+            hash,
+            readahead,
+            self.health.clone(),
+        )?;
+        file.store = self.store.clone();
+        file.compressor = self.compressor.clone();
+        let cache_ino = file.file.metadata()?.ino();
+        let file = Arc::new(Mutex::new(file));
+        files.insert(
+            ino,
+            Fetching {
+                file: Arc::downgrade(&file),
+                cache_ino,
+            },
+        );
+        Ok(file)
+    }
+
+    /// check the cached copy of path, a corrupt one is deleted so it's fetched again, returns
+    /// whether it was, without the hash so it doesn't read the whole file on every open, hash is
+    /// only to drop the stored copy too, which it's likely a link to
+    ///
+    /// nothing is deleted while offline, a copy that may be corrupt beats none at all
+    pub fn discard_corrupt(
+        &self,
+        path: &Path,
+        attr: &FileAttr,
+        hash: Option<&ContentHash>,
+    ) -> bool {
+        if !self.health.is_online() {
+            return false;
+        }
+        match verify(&self.cache_dir, &self.chunks_dir, path, attr, None, true) {
+            Ok(None) => false,
+            Ok(Some(corrupt)) => {
+                warn!("cached {:?} is corrupt, discarding it: {}", path, corrupt);
+                if let (Some(store), Some(hash)) = (&self.store, hash) {
+                    if let Err(e) = store.remove(hash) {
+                        error!("cannot discard stored {:?}: {:?}", path, e);
+                    }
+                }
+                self.discard(path);
+                true
+            }
+            Err(e) => {
+                warn!("cannot verify cached {:?}: {:?}", path, e);
+                false
+            }
+        }
+    }
+
+    pub fn discard(&self, path: &Path) {
+        match discard(&self.cache_dir, &self.chunks_dir, path) {
+            Ok(_) => self.evictor().forget(path),
+            Err(e) => error!("cannot discard cached {:?}: {:?}", path, e),
+        }
+    }
+
+    pub fn evictor(&self) -> MutexGuard<'_, Evictor> {
+        self.evictor.lock().expect("evictor lock poisoned")
+    }
+}
+
+/// fetches whole files in the background, DOWNLOAD_THREADS at a time, while reads are served from
+/// whatever has arrived so far, or read through from the remote for chunks that haven't
+pub struct Downloader;
+
+impl Downloader {
+    pub fn spawn(evictor: Arc<Mutex<Evictor>>) -> std::io::Result<Sender<Arc<Mutex<PartialFile>>>> {
+        let (tx, rx) = std::sync::mpsc::channel::<Arc<Mutex<PartialFile>>>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..DOWNLOAD_THREADS {
+            let rx = rx.clone();
+            let evictor = evictor.clone();
+            std::thread::Builder::new()
+                .name(format!("download-{i}"))
+                .spawn(move || Self::work(&rx, &evictor))?;
+        }
+        Ok(tx)
+    }
+
+    fn work(rx: &Mutex<Receiver<Arc<Mutex<PartialFile>>>>, evictor: &Mutex<Evictor>) {
+        loop {
+            // only hold the lock while waiting, not while downloading
+            let file = match rx.lock().expect("downloader lock poisoned").recv() {
+                Ok(file) => file,
+                Err(_) => return,
+            };
+            let path = file
+                .lock()
+                .expect("partial file lock poisoned")
+                .remote_path
+                .clone();
+            // open as far as eviction goes, even once whoever opened it has closed it
+            evictor.lock().expect("evictor lock poisoned").opened(&path);
+            let ret = download(&file);
+            evictor.lock().expect("evictor lock poisoned").closed(&path);
+            if let Err(e) = ret {
+                // reads will still try the remote themselves
+                error!("download of {:?} failed: {:?}", path, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use crate::{
+    chunk::{self, ContentHash},
+    evict::Evictor,
+    store::Store,
+};
+use log::{debug, error};
+use std::{
+    ffi::CStr,
+    fs::File,
+    io::{BufWriter, Error, ErrorKind, Read, Result, Write},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread::JoinHandle,
+};
+
+pub const DEFAULT_LEVEL: i32 = 3;
+// each frame can be decompressed on its own, so a read only decompresses the frames it covers
+const FRAME_SIZE: u64 = chunk::CHUNK_SIZE;
+// the seek table is a zstd skippable frame at the end, in the zstd seekable format, so a
+// compressed cache file is also a plain .zst that the zstd command line tool can decompress
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+// number of frames, descriptor and magic
+const FOOTER_SIZE: u64 = 9;
+// compressed and decompressed size, without the optional checksum
+const ENTRY_SIZE: u64 = 8;
+// descriptor bit saying every entry has a checksum, which we never write but could read
+const CHECKSUM_FLAG: u8 = 0x80;
+// set on every file we compressed, a remote file that happens to be seekable zstd itself is
+// cached as it is and has to be served that way
+const COMPRESSED_XATTR: &CStr = c"user.cachefs.compressed";
+
+/// where each frame of a compressed file is
+#[derive(Debug)]
+pub struct SeekTable {
+    // (compressed offset, compressed size, plain offset, plain size)
+    frames: Vec<(u64, u64, u64, u64)>,
+    plain_size: u64,
+}
+
+/// mark file as compressed by us, it's kept plain if the filesystem has no user xattrs
+fn mark(file: &File) -> Result<()> {
+    let ret = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            COMPRESSED_XATTR.as_ptr(),
+            b"1".as_ptr().cast(),
+            1,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn is_marked(file: &File) -> bool {
+    let ret = unsafe {
+        libc::fgetxattr(
+            file.as_raw_fd(),
+            COMPRESSED_XATTR.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    ret >= 0
+}
+
+/// the seek table of a compressed cache file, None if it's a plain one
+pub fn seek_table(file: &File) -> Result<Option<SeekTable>> {
+    if !is_marked(file) {
+        return Ok(None);
+    }
+    let len = file.metadata()?.len();
+    if len < FOOTER_SIZE + 8 {
+        return Ok(None);
+    }
+    let mut footer = [0; FOOTER_SIZE as usize];
+    file.read_exact_at(&mut footer, len - FOOTER_SIZE)?;
+    let frames = u32::from_le_bytes(footer[0..4].try_into().expect("4 bytes")) as u64;
+    let descriptor = footer[4];
+    if u32::from_le_bytes(footer[5..9].try_into().expect("4 bytes")) != SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+    let entry_size = ENTRY_SIZE
+        + if descriptor & CHECKSUM_FLAG != 0 {
+            4
+        } else {
+            0
+        };
+    let table_size = frames * entry_size + FOOTER_SIZE;
+    let corrupt = || Error::new(ErrorKind::InvalidData, "corrupt seek table");
+    let start = len.checked_sub(table_size + 8).ok_or_else(corrupt)?;
+    let mut table = vec![0; (table_size + 8) as usize];
+    file.read_exact_at(&mut table, start)?;
+    let u32_at = |i: usize| u32::from_le_bytes(table[i..i + 4].try_into().expect("4 bytes"));
+    if u32_at(0) != SKIPPABLE_MAGIC || u32_at(4) as u64 != table_size {
+        return Ok(None);
+    }
+
+    let mut seek_table = SeekTable {
+        frames: Vec::with_capacity(frames as usize),
+        plain_size: 0,
+    };
+    let mut offset = 0;
+    for i in 0..frames as usize {
+        let entry = 8 + i * entry_size as usize;
+        let (compressed, plain) = (u32_at(entry) as u64, u32_at(entry + 4) as u64);
+        seek_table
+            .frames
+            .push((offset, compressed, seek_table.plain_size, plain));
+        offset += compressed;
+        seek_table.plain_size += plain;
+    }
+    if offset != start {
+        return Err(corrupt());
+    }
+    Ok(Some(seek_table))
+}
+
+/// how big a cache file is decompressed
+pub fn plain_size(file: &File) -> Result<u64> {
+    match seek_table(file)? {
+        Some(table) => Ok(table.plain_size),
+        None => Ok(file.metadata()?.len()),
+    }
+}
+
+/// the contents of a cache file, decompressed if it's compressed
+pub fn plain_reader(file: File) -> Result<Box<dyn Read>> {
+    if seek_table(&file)?.is_some() {
+        // skips the seek table like any other skippable frame
+        Ok(Box::new(zstd::stream::read::Decoder::new(file)?))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// write plain compressed in frames to out, returns the size of out
+fn write_compressed(plain: &File, out: &Path, level: i32) -> Result<u64> {
+    let size = plain.metadata()?.len();
+    let mut compressor = zstd::bulk::Compressor::new(level)?;
+    let mut file = BufWriter::new(File::create(out)?);
+    let mut buf = vec![0; FRAME_SIZE as usize];
+    let mut entries = Vec::new();
+    let mut written = 0;
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(FRAME_SIZE) as usize;
+        plain.read_exact_at(&mut buf[..len], offset)?;
+        let frame = compressor.compress(&buf[..len])?;
+        file.write_all(&frame)?;
+        entries.push((frame.len() as u32, len as u32));
+        written += frame.len() as u64;
+        offset += len as u64;
+    }
+    let table_size = entries.len() as u64 * ENTRY_SIZE + FOOTER_SIZE;
+    file.write_all(&SKIPPABLE_MAGIC.to_le_bytes())?;
+    file.write_all(&(table_size as u32).to_le_bytes())?;
+    for (compressed, plain) in &entries {
+        file.write_all(&compressed.to_le_bytes())?;
+        file.write_all(&plain.to_le_bytes())?;
+    }
+    file.write_all(&(entries.len() as u32).to_le_bytes())?;
+    file.write_all(&[0])?;
+    file.write_all(&SEEKABLE_MAGIC.to_le_bytes())?;
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    mark(&file)?;
+    file.sync_data()?;
+    // verify tells cached copies of this version of the remote file by it
+    file.set_modified(plain.metadata()?.modified()?)?;
+    Ok(written + 8 + table_size)
+}
+
+/// reads a compressed cache file, decompressing only the frames a read covers
+#[derive(Debug)]
+pub struct Reader {
+    file: File,
+    table: SeekTable,
+    // the last frame decompressed, reads are mostly sequential and smaller than a frame
+    last: Mutex<Option<(usize, Vec<u8>)>>,
+}
+
+impl Reader {
+    pub fn new(file: File, table: SeekTable) -> Self {
+        Reader {
+            file,
+            table,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let mut last = self.last.lock().expect("frame cache lock poisoned");
+        let mut i = self
+            .table
+            .frames
+            .partition_point(|(_, _, plain_offset, _)| *plain_offset <= offset)
+            .saturating_sub(1);
+        let mut read = 0;
+        while read < buf.len() {
+            let (compressed_offset, compressed, plain_offset, plain) =
+                match self.table.frames.get(i) {
+                    Some(frame) => *frame,
+                    None => break,
+                };
+            if last.as_ref().is_none_or(|(frame, _)| *frame != i) {
+                let mut frame = vec![0; compressed as usize];
+                self.file.read_exact_at(&mut frame, compressed_offset)?;
+                let frame = zstd::bulk::decompress(&frame, plain as usize)?;
+                *last = Some((i, frame));
+            }
+            let (_, frame) = last.as_ref().expect("just decompressed");
+            let start = (offset + read as u64).saturating_sub(plain_offset) as usize;
+            if start >= frame.len() {
+                break;
+            }
+            let len = (frame.len() - start).min(buf.len() - read);
+            buf[read..read + len].copy_from_slice(&frame[start..start + len]);
+            read += len;
+            i += 1;
+        }
+        Ok(read)
+    }
+}
+
+type Job = (PathBuf, Option<ContentHash>);
+
+/// compresses complete cache files, then adds them to the store if there is one, on a thread of
+/// its own started when the first one comes in, so whatever fetched its last chunk doesn't wait
+pub struct Compressor {
+    level: i32,
+    cache_dir: PathBuf,
+    chunks_dir: PathBuf,
+    tmp_dir: PathBuf,
+    evictor: Arc<Mutex<Evictor>>,
+    store: Option<Store>,
+    worker: Mutex<Option<(Sender<Job>, JoinHandle<()>)>>,
+}
+
+impl std::fmt::Debug for Compressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Compressor {{ level: {} }}", self.level)
+    }
+}
+
+impl Compressor {
+    pub fn new(
+        level: i32,
+        cache_dir: PathBuf,
+        chunks_dir: PathBuf,
+        tmp_dir: PathBuf,
+        evictor: Arc<Mutex<Evictor>>,
+        store: Option<Store>,
+    ) -> Self {
+        Compressor {
+            level,
+            cache_dir,
+            chunks_dir,
+            tmp_dir,
+            evictor,
+            store,
+            worker: Mutex::new(None),
+        }
+    }
+
+    /// compress the complete cache file at cache_path, and store it if hash or the store is given
+    pub fn queue(self: &Arc<Self>, cache_path: PathBuf, hash: Option<ContentHash>) {
+        let mut worker = self.worker.lock().expect("compressor lock poisoned");
+        if worker.is_none() {
+            let (tx, rx) = std::sync::mpsc::channel::<Job>();
+            let compressor = self.clone();
+            let spawned = std::thread::Builder::new()
+                .name("compress".to_string())
+                .spawn(move || {
+                    for (cache_path, hash) in rx {
+                        compressor.compress(&cache_path, hash);
+                    }
+                });
+            match spawned {
+                Ok(handle) => *worker = Some((tx, handle)),
+                Err(e) => {
+                    error!("cannot start compressing: {:?}", e);
+                    return self.compress(&cache_path, hash);
+                }
+            }
+        }
+        if let Some((tx, _)) = worker.as_ref() {
+            tx.send((cache_path, hash)).ok();
+        }
+    }
+
+    /// wait for everything queued to be compressed
+    pub fn finish(&self) {
+        let worker = self.worker.lock().expect("compressor lock poisoned").take();
+        if let Some((tx, handle)) = worker {
+            drop(tx);
+            handle.join().ok();
+        }
+    }
+
+    fn compress(&self, cache_path: &Path, hash: Option<ContentHash>) {
+        if let Err(e) = self.try_compress(cache_path, hash) {
+            // it's still cached, just not compressed
+            error!("cannot compress {:?}: {:?}", cache_path, e);
+        }
+    }
+
+    fn try_compress(&self, cache_path: &Path, hash: Option<ContentHash>) -> Result<()> {
+        let plain = File::open(cache_path)?;
+        let meta = plain.metadata()?;
+        if seek_table(&plain)?.is_some() {
+            return Ok(());
+        }
+        // stored by the hash of the plain contents
+        let hash = match (&self.store, hash) {
+            (Some(_), None) => Some(chunk::hash(&plain)?),
+            (_, hash) => hash,
+        };
+        let path = cache_path
+            .strip_prefix(&self.cache_dir)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "not in the cache"))?;
+
+        let tmp = chunk::tmp_path(&self.tmp_dir)?;
+        let size = match write_compressed(&plain, &tmp, self.level) {
+            Ok(size) => size,
+            Err(e) => {
+                std::fs::remove_file(&tmp).ok();
+                return Err(e);
+            }
+        };
+        // not if it was discarded or changed while we were at it
+        let same = std::fs::metadata(cache_path).is_ok_and(|now| now.ino() == meta.ino())
+            && !chunk::map_path(&self.chunks_dir, path).exists();
+        if !same {
+            return std::fs::remove_file(&tmp);
+        }
+        if size < meta.len() {
+            debug!("compressed {:?} from {} to {size}", path, meta.len());
+            std::fs::rename(&tmp, cache_path)?;
+            self.evictor
+                .lock()
+                .expect("evictor lock poisoned")
+                .resize(path, size);
+        } else {
+            // not worth it, already compressed like most disc images
+            std::fs::remove_file(&tmp)?;
+        }
+        if let (Some(store), Some(hash)) = (&self.store, hash) {
+            store.add(&hash, cache_path)?;
+        }
+        Ok(())
+    }
+}
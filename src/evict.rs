@@ -0,0 +1,309 @@
+use crate::{
+    chunk,
+    pin::{Pins, PINS_NAME},
+    store::Store,
+};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+type SerdeResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub const ACCESS_NAME: &str = "cache-fs.access.zst";
+// how often access times are saved while they're changing, so a crash loses at most this much
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize)]
+struct Access {
+    last_access: SystemTime,
+    size: u64,
+}
+
+/// tracks size and last access of every file under the cache root so the least recently used can
+/// be evicted when the cache grows past max_size, open and pinned files are never evicted
+pub struct Evictor {
+    cache_dir: PathBuf,
+    chunks_dir: PathBuf,
+    save_path: PathBuf,
+    pins_path: PathBuf,
+    // where whatever is evicted actually lives if the cache dir has one
+    store: Option<Store>,
+    max_size: Option<u64>,
+    total_size: u64,
+    // relative path -> access
+    files: HashMap<PathBuf, Access>,
+    // relative path -> open count
+    open: HashMap<PathBuf, usize>,
+    // changed since it was last saved
+    dirty: bool,
+}
+
+impl Evictor {
+    /// load saved access times and reconcile them with what's actually in cache_dir/root, files we
+    /// have no record of get their mtime as last access
+    pub fn load(cache_dir: &Path, max_size: Option<u64>) -> Self {
+        let save_path = cache_dir.join(ACCESS_NAME);
+        let mut saved = match Self::load_saved(&save_path) {
+            Ok(saved) => saved,
+            Err(e) => {
+                if save_path.exists() {
+                    warn!("error loading {:?}: {:?}", save_path, e);
+                }
+                HashMap::new()
+            }
+        };
+        let mut evictor = Evictor {
+            cache_dir: cache_dir.join("root"),
+            chunks_dir: cache_dir.join(chunk::CHUNKS_DIR),
+            save_path,
+            pins_path: cache_dir.join(PINS_NAME),
+            store: Store::open(cache_dir, false).unwrap_or_default(),
+            max_size,
+            total_size: 0,
+            files: HashMap::with_capacity(saved.len()),
+            open: HashMap::new(),
+            dirty: false,
+        };
+
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            let read_dir = match std::fs::read_dir(evictor.cache_dir.join(&dir)) {
+                Ok(read_dir) => read_dir,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    error!("cannot read cache dir {:?}: {:?}", dir, e);
+                    continue;
+                }
+            };
+            for de in read_dir.flatten() {
+                let path = dir.join(de.file_name());
+                let meta = match de.metadata() {
+                    Ok(meta) => meta,
+                    Err(_) => continue,
+                };
+                if meta.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let last_access = match saved.remove(&path) {
+                    Some(access) => access.last_access,
+                    None => meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                };
+                evictor.insert(path, meta.len(), last_access);
+            }
+        }
+        debug!(
+            "cache holds {} files, {} bytes",
+            evictor.files.len(),
+            evictor.total_size
+        );
+        evictor
+    }
+
+    fn load_saved(path: &Path) -> SerdeResult<HashMap<PathBuf, Access>> {
+        let file = File::open(path)?;
+        let file = BufReader::new(file);
+        let file = zstd::stream::Decoder::new(file)?;
+
+        Ok(bincode::deserialize_from(file)?)
+    }
+
+    pub fn save(&mut self) -> SerdeResult<()> {
+        let tmp = self.save_path.with_extension("tmp");
+        {
+            let file = File::create(&tmp)?;
+            let file = BufWriter::new(file);
+            let file = zstd::stream::Encoder::new(file, 3)?.auto_finish();
+            bincode::serialize_into(file, &self.files)?;
+        }
+        std::fs::rename(tmp, &self.save_path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// save evictor every SAVE_INTERVAL if anything changed, rather than only on eviction and
+    /// unmount, which a crash never gets to
+    pub fn spawn_saver(evictor: Arc<Mutex<Evictor>>) -> std::io::Result<JoinHandle<()>> {
+        std::thread::Builder::new()
+            .name("access-save".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(SAVE_INTERVAL);
+                let mut evictor = evictor.lock().expect("evictor lock poisoned");
+                if evictor.dirty {
+                    if let Err(e) = evictor.save() {
+                        error!("cannot save {:?}: {:?}", evictor.save_path, e);
+                    }
+                }
+            })
+    }
+
+    fn insert(&mut self, path: PathBuf, size: u64, last_access: SystemTime) {
+        self.dirty = true;
+        self.total_size += size;
+        if let Some(old) = self.files.insert(path, Access { last_access, size }) {
+            self.total_size -= old.size;
+        }
+    }
+
+    /// record a newly cached file
+    pub fn add(&mut self, path: &Path, size: u64) {
+        self.insert(path.to_path_buf(), size, SystemTime::now());
+    }
+
+    /// record the new size of a cached file, once it's compressed
+    pub fn resize(&mut self, path: &Path, size: u64) {
+        if let Some(access) = self.files.get_mut(path) {
+            self.dirty = true;
+            self.total_size = self.total_size - access.size + size;
+            access.size = size;
+        }
+    }
+
+    /// record an access to an already cached file
+    pub fn touch(&mut self, path: &Path) {
+        if let Some(access) = self.files.get_mut(path) {
+            self.dirty = true;
+            access.last_access = SystemTime::now();
+        }
+    }
+
+    pub fn last_access(&self, path: &Path) -> Option<SystemTime> {
+        self.files.get(path).map(|access| access.last_access)
+    }
+
+    pub fn opened(&mut self, path: &Path) {
+        *self.open.entry(path.to_path_buf()).or_default() += 1;
+    }
+
+    pub fn closed(&mut self, path: &Path) {
+        if let Some(count) = self.open.get_mut(path) {
+            *count -= 1;
+            if *count == 0 {
+                self.open.remove(path);
+            }
+        }
+    }
+
+    /// forget a file (or everything under a directory) that was deleted out from under us
+    pub fn forget(&mut self, path: &Path) {
+        self.dirty = true;
+        if let Some(old) = self.files.remove(path) {
+            self.total_size -= old.size;
+            return;
+        }
+        let mut removed = 0;
+        self.files.retain(|p, access| {
+            let keep = !p.starts_with(path);
+            if !keep {
+                removed += access.size;
+            }
+            keep
+        });
+        self.total_size -= removed;
+    }
+
+    /// evict least recently used files until incoming more bytes fit under max_size
+    pub fn make_room(&mut self, incoming: u64) {
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => return,
+        };
+        if self.total_size + incoming <= max_size {
+            return;
+        }
+
+        // read every time, it's tiny and this way pins from the command line apply immediately
+        let pins = Pins::load(&self.pins_path).unwrap_or_else(|e| {
+            error!("cannot load pins {:?}: {:?}", self.pins_path, e);
+            Pins::default()
+        });
+        let mut lru: Vec<_> = self
+            .files
+            .iter()
+            .filter(|(path, _)| !self.open.contains_key(*path) && !pins.is_pinned(path))
+            .map(|(path, access)| (access.last_access, path.clone()))
+            .collect();
+        lru.sort_unstable();
+
+        let mut evicted = false;
+        for (_, path) in lru {
+            if self.total_size + incoming <= max_size {
+                break;
+            }
+            let full_path = self.cache_dir.join(&path);
+            debug!("evicting {:?}", full_path);
+            match std::fs::remove_file(&full_path) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => {
+                    error!("cannot evict {:?}: {:?}", full_path, e);
+                    continue;
+                }
+            }
+            std::fs::remove_file(chunk::map_path(&self.chunks_dir, &path)).ok();
+            self.forget(&path);
+            self.remove_empty_parents(&path);
+            evicted = true;
+        }
+
+        if self.total_size + incoming > max_size {
+            warn!(
+                "cache over max_cache_size, {} bytes cached + {} incoming > {} and nothing left to evict",
+                self.total_size, incoming, max_size
+            );
+        }
+        if evicted {
+            if let Some(store) = &self.store {
+                // which is also when anything moved or deleted on the remote goes
+                let freed = store.prune();
+                debug!("pruned {freed} bytes from the store");
+            }
+            if let Err(e) = self.save() {
+                error!("cannot save {:?}: {:?}", self.save_path, e);
+            }
+        }
+    }
+
+    fn remove_empty_parents(&self, path: &Path) {
+        // remove_dir only succeeds on empty directories, so just stop at the first failure
+        for parent in path.ancestors().skip(1) {
+            if parent.as_os_str().is_empty()
+                || std::fs::remove_dir(self.cache_dir.join(parent)).is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
+/// parse a size like 1024, 512M or 20G, suffixes are powers of 1024
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (num, mult) = match size.char_indices().last()? {
+        (i, 'k' | 'K') => (&size[..i], 1 << 10),
+        (i, 'm' | 'M') => (&size[..i], 1 << 20),
+        (i, 'g' | 'G') => (&size[..i], 1 << 30),
+        (i, 't' | 'T') => (&size[..i], 1 << 40),
+        _ => (size, 1),
+    };
+    num.parse::<u64>().ok()?.checked_mul(mult)
+}
+
+/// bytes with a K/M/G/T suffix, powers of 1024 like parse_size
+pub fn human(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for suffix in ["", "K", "M", "G"] {
+        if size < 1024.0 {
+            return format!("{:.1}{suffix}", size);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1}T", size)
+}
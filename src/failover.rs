@@ -0,0 +1,243 @@
+use crate::{
+    health::Health,
+    remote::{RemoteBackend, RemoteFile, Stat},
+    FileTree, Result,
+};
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt::{Debug, Formatter},
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// several remotes with the same files, e.g. a NAS over the LAN and then the same share over a
+/// VPN, in order of priority
+///
+/// the tree comes from the first one, so the index is the same as with only that one, and re-scans
+/// wait for it (unless it was down when the cache dir was first mounted, see main). Files are
+/// fetched from the first one that's online, the others only for files whose size and mtime there
+/// agree with the tree, so one that's out of date or a different share entirely is never cached
+/// from, which is checked once per file until it goes offline or comes back
+#[derive(Clone)]
+pub struct Failover {
+    remotes: Arc<Vec<Member>>,
+    tree: Arc<RwLock<FileTree>>,
+}
+
+struct Member {
+    remote: Arc<dyn RemoteBackend>,
+    health: Arc<Health>,
+    agreed: Mutex<Agreed>,
+}
+
+/// what agrees found on a remote since its health epoch last changed, by path, along with the size
+/// and mtime the tree had then so a rescan that changes the file asks again
+#[derive(Default)]
+struct Agreed {
+    epoch: u64,
+    paths: HashMap<PathBuf, (u64, SystemTime, bool)>,
+}
+
+impl Debug for Failover {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.remotes.iter().map(|member| &member.remote))
+            .finish()
+    }
+}
+
+impl Failover {
+    /// each remote gets its own health, probed whenever this is
+    pub fn new(
+        remotes: Vec<Arc<dyn RemoteBackend>>,
+        tree: Arc<RwLock<FileTree>>,
+        timeout: Duration,
+    ) -> Self {
+        let remotes = remotes
+            .into_iter()
+            .map(|remote| Member {
+                remote: remote.clone(),
+                health: Arc::new(Health::new(remote, timeout)),
+                agreed: Mutex::new(Agreed::default()),
+            })
+            .collect();
+        Failover {
+            remotes: Arc::new(remotes),
+            tree,
+        }
+    }
+
+    fn primary(&self) -> Result<&dyn RemoteBackend> {
+        let primary = &self.remotes[0];
+        primary.health.check()?;
+        Ok(primary.remote.as_ref())
+    }
+
+    /// whether member has the same version of path as the tree, to the second as that's all some
+    /// remotes keep
+    fn agrees(&self, member: &Member, path: &Path) -> bool {
+        let expected = {
+            let tree = self.tree.read().expect("tree lock poisoned");
+            match tree.resolve(path).and_then(|ino| tree.file(ino)) {
+                Some(file) => file.attr,
+                None => return false,
+            }
+        };
+        let epoch = member.health.epoch();
+        {
+            let mut agreed = member.agreed.lock().expect("agreed lock poisoned");
+            if agreed.epoch != epoch {
+                *agreed = Agreed {
+                    epoch,
+                    paths: HashMap::new(),
+                };
+            }
+            match agreed.paths.get(path) {
+                Some(&(size, mtime, agrees))
+                    if size == expected.size && mtime == expected.mtime =>
+                {
+                    return agrees
+                }
+                _ => (),
+            }
+        }
+
+        let secs = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        let agrees = match member.remote.stat(path) {
+            Ok(Stat { attr, .. }) => {
+                attr.size == expected.size && secs(attr.mtime) == secs(expected.mtime)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => {
+                // not remembered, it may well work next time
+                debug!("cannot stat {:?} on {:?}: {:?}", path, member.remote, e);
+                return false;
+            }
+        };
+        let mut agreed = member.agreed.lock().expect("agreed lock poisoned");
+        if agreed.epoch == epoch {
+            agreed
+                .paths
+                .insert(path.to_path_buf(), (expected.size, expected.mtime, agrees));
+        }
+        agrees
+    }
+
+    /// open path on the first remote that's online and agrees with the tree, apart from skip,
+    /// returns which one it was
+    fn open_from(&self, path: &Path, skip: Option<usize>) -> Result<(usize, Box<dyn RemoteFile>)> {
+        let mut first_error = None;
+        for (i, member) in self.remotes.iter().enumerate() {
+            let remote = &member.remote;
+            if Some(i) == skip || !member.health.is_online() {
+                continue;
+            }
+            // the tree came from the first one
+            if i > 0 && !self.agrees(member, path) {
+                debug!(
+                    "{:?} on {:?} doesn't match the tree, skipping it",
+                    path, remote
+                );
+                continue;
+            }
+            match remote.open(path) {
+                Ok(file) => {
+                    if i > 0 {
+                        debug!("fetching {:?} from {:?}", path, remote);
+                    }
+                    return Ok((i, file));
+                }
+                Err(e) => {
+                    warn!("cannot open {:?} on {:?}: {:?}", path, remote, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        // nothing online had it, which is as good as offline
+        Err(first_error.unwrap_or_else(|| Error::from_raw_os_error(libc::EHOSTDOWN)))
+    }
+}
+
+impl RemoteBackend for Failover {
+    fn read_dir(&self, dir: &Path) -> Result<Vec<(OsString, Stat)>> {
+        self.primary()?.read_dir(dir)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat> {
+        self.primary()?.stat(path)
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        self.primary()?.read_link(path)
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RemoteFile>> {
+        let current = self.open_from(path, None)?;
+        Ok(Box::new(FailoverFile {
+            failover: self.clone(),
+            path: path.to_path_buf(),
+            current: Mutex::new(current),
+        }))
+    }
+
+    fn probe(&self) -> Result<()> {
+        // all at once, a hung one shouldn't keep the rest from counting
+        let (tx, rx) = mpsc::channel();
+        for member in self.remotes.iter() {
+            let health = member.health.clone();
+            let tx = tx.clone();
+            std::thread::Builder::new()
+                .name("probe".to_string())
+                .spawn(move || {
+                    tx.send(health.probe()).ok();
+                })?;
+        }
+        drop(tx);
+        // any one online is enough, the rest still update their own health when they answer
+        if rx.iter().any(|online| online) {
+            Ok(())
+        } else {
+            Err(Error::from_raw_os_error(libc::EHOSTDOWN))
+        }
+    }
+}
+
+/// a file open on one of the remotes, moving on to the next if reading it fails
+struct FailoverFile {
+    failover: Failover,
+    path: PathBuf,
+    // which remote and the file open on it
+    current: Mutex<(usize, Box<dyn RemoteFile>)>,
+}
+
+impl Debug for FailoverFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FailoverFile {{ path: {:?} }}", self.path)
+    }
+}
+
+impl RemoteFile for FailoverFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let mut current = self.current.lock().expect("current file lock poisoned");
+        match current.1.read_at(buf, offset) {
+            Err(e) if e.kind() != ErrorKind::Interrupted => {
+                let failed = current.0;
+                warn!(
+                    "reading {:?} from {:?} failed, trying the other remotes: {:?}",
+                    self.path, self.failover.remotes[failed].remote, e
+                );
+                *current = self.failover.open_from(&self.path, Some(failed))?;
+                current.1.read_at(buf, offset)
+            }
+            ret => ret,
+        }
+    }
+}
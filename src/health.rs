@@ -0,0 +1,116 @@
+use crate::remote::RemoteBackend;
+use log::{debug, info, warn};
+use std::{
+    io::Error,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// how often the remote is probed while mounted
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// whether the remote answers in time, so anything that would have to wait on it can fail fast
+/// with EHOSTDOWN instead of hanging when e.g. an NFS server goes away
+#[derive(Debug)]
+pub struct Health {
+    remote: Arc<dyn RemoteBackend>,
+    timeout: Duration,
+    online: AtomicBool,
+    // bumped whenever it goes offline or comes back, see epoch
+    epoch: AtomicU64,
+    // set while a probe is running, one stuck on a hung mount is left to finish on its own time
+    probing: Arc<AtomicBool>,
+}
+
+impl Health {
+    /// assumed online until a probe says otherwise
+    pub fn new(remote: Arc<dyn RemoteBackend>, timeout: Duration) -> Self {
+        Health {
+            remote,
+            timeout,
+            online: AtomicBool::new(true),
+            epoch: AtomicU64::new(0),
+            probing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Relaxed)
+    }
+
+    /// how many times the remote has gone offline or come back, so whatever was learnt about it
+    /// can be kept until this changes
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    /// Err(EHOSTDOWN) if the remote is offline
+    pub fn check(&self) -> std::io::Result<()> {
+        if self.is_online() {
+            Ok(())
+        } else {
+            Err(Error::from_raw_os_error(libc::EHOSTDOWN))
+        }
+    }
+
+    /// probe the remote on a separate thread and wait at most timeout for it, returns whether the
+    /// remote is online now
+    pub fn probe(&self) -> bool {
+        let online = if self.probing.swap(true, Ordering::AcqRel) {
+            // the last probe still hasn't returned, don't pile up another thread behind it
+            false
+        } else {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let remote = self.remote.clone();
+            let probing = self.probing.clone();
+            let spawned = std::thread::Builder::new()
+                .name("probe".to_string())
+                .spawn(move || {
+                    let ret = remote.probe();
+                    probing.store(false, Ordering::Release);
+                    tx.send(ret).ok();
+                });
+            match spawned {
+                Ok(_) => match rx.recv_timeout(self.timeout) {
+                    Ok(Ok(_)) => true,
+                    Ok(Err(e)) => {
+                        debug!("probe of {:?} failed: {:?}", self.remote, e);
+                        false
+                    }
+                    Err(_) => {
+                        debug!("probe of {:?} timed out", self.remote);
+                        false
+                    }
+                },
+                Err(e) => {
+                    self.probing.store(false, Ordering::Release);
+                    warn!("cannot start probe thread: {:?}", e);
+                    self.is_online()
+                }
+            }
+        };
+        if self.online.swap(online, Ordering::Relaxed) != online {
+            self.epoch.fetch_add(1, Ordering::AcqRel);
+            if online {
+                info!("remote {:?} is back online", self.remote);
+            } else {
+                warn!("remote {:?} is offline", self.remote);
+            }
+        }
+        online
+    }
+
+    pub fn spawn(self: Arc<Self>) -> std::io::Result<()> {
+        std::thread::Builder::new()
+            .name("health".to_string())
+            .spawn(move || loop {
+                self.probe();
+                std::thread::sleep(PROBE_INTERVAL);
+            })?;
+        Ok(())
+    }
+}
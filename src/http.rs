@@ -0,0 +1,517 @@
+use crate::{
+    chunk,
+    index::INDEX_NAME,
+    remote::{self, RemoteBackend, RemoteFile, Stat},
+    FileTree, Result,
+};
+use log::debug;
+use std::{
+    ffi::OsString,
+    fmt::{Debug, Formatter, Write},
+    io::{Error, ErrorKind},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// a remote served over HTTP(S) by any web server that does range requests, directories can't be
+/// listed over HTTP so it needs an index built on the server with -c, which listings come from
+pub struct Http {
+    // without a trailing slash
+    url: String,
+    agent: ureq::Agent,
+    // the remote's index and its ETag or Last-Modified, so it's only fetched again if it changed
+    index: Mutex<Option<(Option<String>, Arc<FileTree>)>>,
+}
+
+impl Debug for Http {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Http {{ url: {:?} }}", self.url)
+    }
+}
+
+impl Http {
+    pub fn new(url: &str) -> Self {
+        Http {
+            url: url.trim_end_matches('/').to_string(),
+            agent: agent(),
+            index: Mutex::new(None),
+        }
+    }
+
+    fn url(&self, path: &Path) -> String {
+        format!(
+            "{}/{}",
+            self.url,
+            percent_encode(path.as_os_str().as_bytes(), true)
+        )
+    }
+
+    /// the remote's index, fetched again first if refresh and it changed
+    fn tree(&self, refresh: bool) -> Result<Arc<FileTree>> {
+        let mut index = self.index.lock().expect("index lock poisoned");
+        let version = match index.as_ref() {
+            Some((_, tree)) if !refresh => return Ok(tree.clone()),
+            Some((Some(old), tree)) => match self.version() {
+                Some(version) if version == *old => return Ok(tree.clone()),
+                version => version,
+            },
+            _ => self.version(),
+        };
+        debug!("fetching the index of {}", self.url);
+        let tmp = chunk::tmp_path(&std::env::temp_dir())?;
+        let tree = FileTree::load_remote(self, &tmp)
+            .map_err(|e| Error::other(e.to_string()))?
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "{} has no {INDEX_NAME}, build one on the server with -c",
+                        self.url
+                    ),
+                )
+            })?;
+        let tree = Arc::new(tree);
+        *index = Some((version, tree.clone()));
+        Ok(tree)
+    }
+
+    /// what the web server says the version of the index is, if it says
+    fn version(&self) -> Option<String> {
+        let response = self
+            .agent
+            .head(&self.url(Path::new(INDEX_NAME)))
+            .call()
+            .ok()?;
+        response
+            .header("ETag")
+            .or_else(|| response.header("Last-Modified"))
+            .map(str::to_string)
+    }
+}
+
+/// an agent with the timeouts of every remote over a network
+pub fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(remote::CONNECT_TIMEOUT)
+        .timeout_read(remote::READ_TIMEOUT)
+        .build()
+}
+
+/// send request for the range of a file of size (if known) at offset that fits in buf, and read
+/// the answer into it, returns how much was read, 0 at the end of the file
+pub fn read_range(
+    request: ureq::Request,
+    buf: &mut [u8],
+    offset: u64,
+    size: Option<u64>,
+) -> Result<usize> {
+    if buf.is_empty() || size.is_some_and(|size| offset >= size) {
+        return Ok(0);
+    }
+    let range = format!("bytes={offset}-{}", offset + buf.len() as u64 - 1);
+    let response = match request.set("Range", &range).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(416, _)) => return Ok(0),
+        Err(e) => return Err(error(e)),
+    };
+    // the server ignored the range and is sending all of it, which is fine from the start but
+    // anywhere else would mean downloading everything before offset again for every chunk
+    if response.status() != 206 && offset > 0 {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("the server ignores range requests, cannot read from {offset}"),
+        ));
+    }
+    remote::fill(&mut response.into_reader(), buf)
+}
+
+/// for a url, everything but letters, digits and -._~ (and slashes if keep_slashes)
+pub fn percent_encode(bytes: &[u8], keep_slashes: bool) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || (keep_slashes && b == b'/') {
+            encoded.push(b as char);
+        } else {
+            write!(encoded, "%{b:02X}").expect("writing to a string");
+        }
+    }
+    encoded
+}
+
+pub fn error(e: ureq::Error) -> Error {
+    let kind = match &e {
+        ureq::Error::Status(404 | 410, _) => ErrorKind::NotFound,
+        ureq::Error::Status(401 | 403, _) => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+    Error::new(kind, e.to_string())
+}
+
+fn stat(tree: &FileTree, ino: u64) -> Option<Stat> {
+    let file = tree.file(ino)?;
+    // every name of a hard linked file is the same entry of the index
+    let link_id = (!file.links.is_empty()).then_some((0, ino));
+    Some(Stat {
+        attr: file.attr,
+        link_id,
+    })
+}
+
+fn not_found() -> Error {
+    Error::from(ErrorKind::NotFound)
+}
+
+impl RemoteBackend for Http {
+    fn read_dir(&self, dir: &Path) -> Result<Vec<(OsString, Stat)>> {
+        let tree = self.tree(false)?;
+        let children = tree
+            .resolve(dir)
+            .and_then(|ino| tree.folder(ino))
+            .ok_or_else(not_found)?;
+        Ok(children
+            .iter()
+            .filter_map(|(name, ino)| Some((name.clone(), stat(&tree, *ino)?)))
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat> {
+        // every scan starts with the root, so that's when a changed index is picked up
+        let tree = self.tree(path.as_os_str().is_empty())?;
+        tree.resolve(path)
+            .and_then(|ino| stat(&tree, ino))
+            .ok_or_else(not_found)
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        let tree = self.tree(false)?;
+        tree.resolve(path)
+            .and_then(|ino| tree.symlink(ino))
+            .map(|target| PathBuf::from(target.into_owned()))
+            .ok_or_else(not_found)
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RemoteFile>> {
+        let url = self.url(path);
+        let response = self.agent.head(&url).call().map_err(error)?;
+        let size = response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok());
+        Ok(Box::new(HttpFile {
+            agent: self.agent.clone(),
+            url,
+            size,
+        }))
+    }
+
+    fn probe(&self) -> Result<()> {
+        match self.agent.head(&self.url(Path::new(""))).call() {
+            // any answer at all means the server is up
+            Ok(_) | Err(ureq::Error::Status(..)) => Ok(()),
+            Err(e) => Err(error(e)),
+        }
+    }
+}
+
+struct HttpFile {
+    agent: ureq::Agent,
+    url: String,
+    size: Option<u64>,
+}
+
+impl Debug for HttpFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HttpFile {{ url: {:?} }}", self.url)
+    }
+}
+
+impl RemoteFile for HttpFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        read_range(self.agent.get(&self.url), buf, offset, self.size)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::remote::Local;
+    use fuser::FileType;
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write as _},
+        net::{TcpListener, TcpStream},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// what the test server was asked
+    pub(crate) struct Request {
+        pub method: String,
+        // path and query, still percent encoded
+        pub target: String,
+        // names in lowercase
+        pub headers: HashMap<String, String>,
+    }
+
+    pub(crate) struct Response {
+        pub status: u16,
+        pub headers: Vec<(&'static str, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl Response {
+        pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+            Response {
+                status,
+                headers: Vec::new(),
+                body: body.into(),
+            }
+        }
+    }
+
+    /// a web server on localhost answering every request with respond, returns its url
+    pub(crate) fn serve(respond: impl Fn(&Request) -> Response + Send + Sync + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("cannot listen on localhost");
+        let url = format!(
+            "http://{}",
+            listener.local_addr().expect("no local address")
+        );
+        let respond = Arc::new(respond);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let respond = respond.clone();
+                std::thread::spawn(move || handle(stream, respond.as_ref()).ok());
+            }
+        });
+        url
+    }
+
+    fn handle(stream: TcpStream, respond: &dyn Fn(&Request) -> Response) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut words = line.split_whitespace();
+        let method = words.next().unwrap_or_default().to_string();
+        let target = words.next().unwrap_or_default().to_string();
+        let mut headers = HashMap::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => {
+                    headers.insert(name.to_ascii_lowercase(), value.trim().to_string())
+                }
+                None => break,
+            };
+        }
+        let request = Request {
+            method,
+            target,
+            headers,
+        };
+        let response = respond(&request);
+        // a connection per request keeps this simple, ureq just opens another
+        let mut out = stream;
+        write!(
+            out,
+            "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+            response.status,
+            response.body.len()
+        )?;
+        for (name, value) in &response.headers {
+            write!(out, "{name}: {value}\r\n")?;
+        }
+        out.write_all(b"\r\n")?;
+        if request.method != "HEAD" {
+            out.write_all(&response.body)?;
+        }
+        out.flush()
+    }
+
+    /// a web server with the files in files by their url path, honouring ranges if ranges
+    struct Server {
+        url: String,
+        files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        // GETs of the index from its start, so once per time it's fetched
+        index_fetches: Arc<AtomicUsize>,
+    }
+
+    impl Server {
+        fn new(ranges: bool) -> Self {
+            let files = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+            let index_fetches = Arc::new(AtomicUsize::new(0));
+            let index_url = format!("/{INDEX_NAME}");
+            let url = serve({
+                let files = files.clone();
+                let index_fetches = index_fetches.clone();
+                move |request| {
+                    let files = files.lock().unwrap();
+                    let file = match files.get(&request.target) {
+                        Some(file) => file,
+                        None => return Response::new(404, ""),
+                    };
+                    let etag = format!("\"{}\"", blake3::hash(file).to_hex());
+                    let range = request
+                        .headers
+                        .get("range")
+                        .and_then(|range| range.strip_prefix("bytes="))
+                        .and_then(|range| range.split_once('-'))
+                        .map(|(start, end)| {
+                            (
+                                start.parse::<usize>().unwrap(),
+                                end.parse::<usize>().unwrap(),
+                            )
+                        })
+                        .filter(|_| ranges && request.method == "GET");
+                    if request.method == "GET"
+                        && request.target == index_url
+                        && range.is_none_or(|(start, _)| start == 0)
+                    {
+                        index_fetches.fetch_add(1, Ordering::SeqCst);
+                    }
+                    let mut response = match range {
+                        Some((start, _)) if start >= file.len() => Response::new(416, ""),
+                        Some((start, end)) => {
+                            let end = end.min(file.len() - 1);
+                            let mut response = Response::new(206, &file[start..=end]);
+                            response.headers.push((
+                                "Content-Range",
+                                format!("bytes {start}-{end}/{}", file.len()),
+                            ));
+                            response
+                        }
+                        None => Response::new(200, file.clone()),
+                    };
+                    response.headers.push(("ETag", etag));
+                    response
+                }
+            });
+            Server {
+                url,
+                files,
+                index_fetches,
+            }
+        }
+
+        /// serve files, given as (path, contents), and an index of them built like -c does
+        fn publish(&self, files: &[(&str, &[u8])]) {
+            let dir = std::env::temp_dir().join(format!(
+                "cache-fs-http-test-{}",
+                self.url.rsplit(':').next().unwrap()
+            ));
+            std::fs::remove_dir_all(&dir).ok();
+            let mut served = HashMap::new();
+            for (path, contents) in files {
+                let path = dir.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, contents).unwrap();
+            }
+            FileTree::build(&Local::new(dir.clone()))
+                .save(&dir.join(INDEX_NAME), &dir)
+                .unwrap();
+            for (path, _) in files.iter().chain(&[(INDEX_NAME, &[][..])]) {
+                let url = format!("/{}", percent_encode(path.as_bytes(), true));
+                served.insert(url, std::fs::read(dir.join(path)).unwrap());
+            }
+            std::fs::remove_dir_all(&dir).ok();
+            *self.files.lock().unwrap() = served;
+        }
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn read_all(file: &dyn RemoteFile, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn ranges() {
+        let big = contents(300_000);
+        let server = Server::new(true);
+        server.publish(&[("dir/big file", &big), ("small", b"hello")]);
+        let http = Http::new(&server.url);
+
+        let root: Vec<_> = http.read_dir(Path::new("")).unwrap();
+        assert_eq!(root.len(), 2);
+        assert_eq!(
+            http.stat(Path::new("dir/big file")).unwrap().attr.size,
+            big.len() as u64
+        );
+        assert_eq!(
+            http.stat(Path::new("missing")).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        let file = http.open(Path::new("dir/big file")).unwrap();
+        let mut buf = vec![0; 1000];
+        assert_eq!(file.read_at(&mut buf, 123_456).unwrap(), 1000);
+        assert_eq!(buf, big[123_456..124_456]);
+        // past the end is cut short, and then nothing
+        assert_eq!(file.read_at(&mut buf, 299_500).unwrap(), 500);
+        assert_eq!(buf[..500], big[299_500..]);
+        assert_eq!(file.read_at(&mut buf, 300_000).unwrap(), 0);
+        assert_eq!(read_all(file.as_ref(), big.len()), big);
+    }
+
+    #[test]
+    fn server_ignoring_ranges() {
+        let big = contents(300_000);
+        let server = Server::new(false);
+        server.publish(&[("big", &big)]);
+        let http = Http::new(&server.url);
+
+        let file = http.open(Path::new("big")).unwrap();
+        let mut buf = vec![0; 1000];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), 1000);
+        assert_eq!(buf, big[..1000]);
+        // rather than fetching the 200000 bytes before it again
+        let e = file.read_at(&mut buf, 200_000).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn range_past_the_end() {
+        let server = Server::new(true);
+        server.publish(&[("small", b"hello")]);
+        // as if the server left out Content-Length, so only the 416 says it's the end
+        let file = HttpFile {
+            agent: ureq::agent(),
+            url: format!("{}/small", server.url),
+            size: None,
+        };
+        let mut buf = [0; 10];
+        assert_eq!(file.read_at(&mut buf, 5).unwrap(), 0);
+        assert_eq!(file.read_at(&mut buf, 2).unwrap(), 3);
+        assert_eq!(&buf[..3], b"llo");
+        assert_eq!(
+            file.read_exact_at(&mut buf, 0).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn index_refreshed_by_etag() {
+        let server = Server::new(true);
+        server.publish(&[("a", b"a")]);
+        let http = Http::new(&server.url);
+
+        http.stat(Path::new("")).unwrap();
+        assert_eq!(server.index_fetches.load(Ordering::SeqCst), 1);
+        // the same ETag, so the index isn't fetched again
+        http.stat(Path::new("")).unwrap();
+        assert_eq!(server.index_fetches.load(Ordering::SeqCst), 1);
+
+        server.publish(&[("a", b"a"), ("b", b"bb")]);
+        // only a scan, which starts at the root, picks it up
+        assert!(http.stat(Path::new("b")).is_err());
+        assert_eq!(
+            http.stat(Path::new("")).unwrap().attr.kind,
+            FileType::Directory
+        );
+        assert_eq!(server.index_fetches.load(Ordering::SeqCst), 2);
+        assert_eq!(http.stat(Path::new("b")).unwrap().attr.size, 2);
+    }
+}
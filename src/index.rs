@@ -0,0 +1,888 @@
+use crate::{
+    chunk::{self, ContentHash},
+    remote::{self, RemoteBackend},
+    FileAttrDef, FileInfo, FileTree, SerdeResult, TypeExtra,
+};
+use fuser::{FileAttr, FileType};
+use log::{info, warn};
+use memmap2::Mmap;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt::{Display, Formatter},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+pub const INDEX_NAME: &str = "cache-fs.index";
+const MAGIC: &[u8; 8] = b"CACHEFS\0";
+// bump this and add a case to upgrade whenever the layout or anything in it changes
+const VERSION: u32 = 5;
+// versions from before the header existed, only recognizable by their name
+const LEGACY: &[(&str, u32)] = &[("cache-fs.tree.zst", 1), ("cache-fs.tree.v2.zst", 2)];
+// serialized size of a Child or a Link
+const ENTRY_SIZE: u64 = 24;
+
+/// everything after the magic and version of a version 3 index, compressed
+#[derive(Serialize, Deserialize)]
+struct Header {
+    // what remote_dir this was built from, so a cache dir can't be used with the wrong remote
+    remote: PathBuf,
+}
+
+/// a run of bytes in the strings section, or of entries in one of the tables
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct Span {
+    start: u64,
+    len: u64,
+}
+
+/// follows the magic and version, where everything is in the file, which is never compressed so
+/// it can be mapped and only the entries actually looked up are decoded
+#[derive(Serialize, Deserialize)]
+struct Layout {
+    // the remote_dir it was built from, in strings
+    remote: Span,
+    max_ino: u64,
+    record_size: u64,
+    // file offsets of the tables and how many entries they have
+    records: Span,
+    children: Span,
+    links: Span,
+    // file offset and length of every name and symlink target, spans in entries are relative to it
+    strings: Span,
+}
+
+/// one per inode, sorted by inode
+#[derive(Serialize, Deserialize)]
+struct Record {
+    // must come first, it's read without decoding the rest for the binary search
+    ino: u64,
+    parent: u64,
+    // full paths are rebuilt from the names of the parents
+    name: Span,
+    #[serde(with = "FileAttrDef")]
+    attr: FileAttr,
+    // children of a directory, target of a symlink or every other name of a hard linked file
+    extra: Span,
+    // of a regular file's contents, all zeros if it isn't known
+    hash: ContentHash,
+}
+
+/// one per name in a directory, the children of each directory are sorted by name
+#[derive(Serialize, Deserialize)]
+struct Child {
+    name: Span,
+    ino: u64,
+}
+
+/// another name of a hard linked file
+#[derive(Serialize, Deserialize)]
+struct Link {
+    parent: u64,
+    name: Span,
+}
+
+#[derive(Debug)]
+pub enum IndexError {
+    NotAnIndex(PathBuf),
+    TooNew {
+        path: PathBuf,
+        version: u32,
+    },
+    WrongRemote {
+        path: PathBuf,
+        remote: PathBuf,
+        expected: PathBuf,
+    },
+    Corrupt {
+        path: PathBuf,
+        version: u32,
+        error: Box<dyn std::error::Error>,
+    },
+}
+
+impl Display for IndexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IndexError::NotAnIndex(path) => write!(f, "{:?} is not a cache-fs index", path),
+            IndexError::TooNew { path, version } => write!(
+                f,
+                "{:?} is index version {version} but this cache-fs only understands up to {VERSION}, upgrade cache-fs or delete it to rebuild",
+                path
+            ),
+            IndexError::WrongRemote {
+                path,
+                remote,
+                expected,
+            } => write!(
+                f,
+                "{:?} was built for remote_dir {:?} not {:?}, use a different cache dir or delete it to rebuild",
+                path, remote, expected
+            ),
+            IndexError::Corrupt {
+                path,
+                version,
+                error,
+            } => write!(
+                f,
+                "{:?} (index version {version}) is corrupt, delete it to rebuild: {error}",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+/// true for the index and any legacy ones, so they're never shown in the mount
+pub fn is_index_name(name: &OsStr) -> bool {
+    name == INDEX_NAME || LEGACY.iter().any(|(legacy, _)| name == *legacy)
+}
+
+/// a current index mapped into memory
+pub struct Mapped {
+    map: Mmap,
+    layout: Layout,
+    // the mapped layout is also used to upgrade version 4, which had no hashes in its records
+    version: u32,
+}
+
+impl Mapped {
+    fn open(file: &File, version: u32) -> SerdeResult<Self> {
+        // index files are only ever replaced by renaming a new one over them, never written in
+        // place, so the mapping can't change underneath us
+        let map = unsafe { Mmap::map(file)? };
+        let start = MAGIC.len() + 4;
+        let layout: Layout = bincode::deserialize(map.get(start..).unwrap_or_default())?;
+        let len = map.len() as u64;
+        let fits = |table: Span, size: u64| {
+            table
+                .len
+                .checked_mul(size)
+                .and_then(|bytes| bytes.checked_add(table.start))
+                .is_some_and(|end| end <= len)
+        };
+        if layout.record_size < 8
+            || !fits(layout.records, layout.record_size)
+            || !fits(layout.children, ENTRY_SIZE)
+            || !fits(layout.links, ENTRY_SIZE)
+            || !fits(layout.strings, 1)
+        {
+            return Err("tables don't fit in the file".into());
+        }
+        Ok(Mapped {
+            map,
+            layout,
+            version,
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(&self, offset: u64, size: u64) -> Option<T> {
+        let bytes = self.map.get(offset as usize..(offset + size) as usize)?;
+        bincode::deserialize(bytes).ok()
+    }
+
+    fn string(&self, span: Span) -> Option<&OsStr> {
+        let start = self.layout.strings.start.checked_add(span.start)?;
+        let end = start.checked_add(span.len)?;
+        if end > self.layout.strings.start + self.layout.strings.len {
+            return None;
+        }
+        Some(OsStr::from_bytes(
+            self.map.get(start as usize..end as usize)?,
+        ))
+    }
+
+    fn ino_at(&self, i: u64) -> Option<u64> {
+        let offset = (self.layout.records.start + i * self.layout.record_size) as usize;
+        let bytes = self.map.get(offset..offset + 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn record(&self, ino: u64) -> Option<Record> {
+        let (mut low, mut high) = (0, self.layout.records.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.ino_at(mid)?.cmp(&ino) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => {
+                    let size = self.layout.record_size;
+                    let offset = self.layout.records.start + mid * size;
+                    return match self.version {
+                        4 => self.decode::<v4::Record>(offset, size).map(Record::from),
+                        _ => self.decode(offset, size),
+                    };
+                }
+            }
+        }
+        None
+    }
+
+    fn entry<T: DeserializeOwned>(&self, table: Span, run: Span, i: u64) -> Option<T> {
+        let i = run.start.checked_add(i).filter(|_| i < run.len)?;
+        if i >= table.len {
+            return None;
+        }
+        self.decode(table.start + i * ENTRY_SIZE, ENTRY_SIZE)
+    }
+
+    fn child_at(&self, dir: &Record, i: u64) -> Option<(&OsStr, u64)> {
+        let child: Child = self.entry(self.layout.children, dir.extra, i)?;
+        Some((self.string(child.name)?, child.ino))
+    }
+
+    /// binary search of the sorted children of dir
+    fn child(&self, dir: &Record, name: &OsStr) -> Option<u64> {
+        let (mut low, mut high) = (0, dir.extra.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let (child, ino) = self.child_at(dir, mid)?;
+            match child.as_bytes().cmp(name.as_bytes()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(ino),
+            }
+        }
+        None
+    }
+
+    fn links(&self, file: &Record) -> Option<Vec<(u64, &OsStr)>> {
+        (0..file.extra.len)
+            .map(|i| {
+                let link: Link = self.entry(self.layout.links, file.extra, i)?;
+                Some((link.parent, self.string(link.name)?))
+            })
+            .collect()
+    }
+
+    fn inos(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.layout.records.len).filter_map(|i| self.ino_at(i))
+    }
+}
+
+/// append s to strings
+fn push_string(strings: &mut Vec<u8>, s: &OsStr) -> Span {
+    let span = Span {
+        start: strings.len() as u64,
+        len: s.len() as u64,
+    };
+    strings.extend_from_slice(s.as_bytes());
+    span
+}
+
+impl FileTree {
+    /// the cache dir's index, migrating it from an older version if needed, or else a copy of one
+    /// built on the remote with -c, or else a fresh scan of the remote, remote_dir is what it's
+    /// recorded as built from
+    pub fn load_or_build(
+        remote: &dyn RemoteBackend,
+        remote_dir: &Path,
+        cache_path: &Path,
+    ) -> SerdeResult<Self> {
+        let path = cache_path.join(INDEX_NAME);
+        let tree = if path.exists() {
+            // an index we can't use is an error, rebuilding would lose every inode number
+            let tree = FileTree::load(&path, Some(remote_dir))?;
+            if tree.index.is_some() {
+                return Ok(tree);
+            }
+            tree
+        } else if let Some((legacy, version)) = LEGACY
+            .iter()
+            .map(|(name, version)| (cache_path.join(name), *version))
+            .find(|(legacy, _)| legacy.exists())
+        {
+            let tree = load_legacy(&legacy, version)?;
+            tree.save(&path, remote_dir)?;
+            std::fs::remove_file(&legacy)?;
+            info!("migrated {:?} to {:?}", legacy, path);
+            tree
+        } else {
+            // one built on the remote, whatever path it was built from is fine
+            FileTree::load_remote(remote, &chunk::tmp_path(&cache_path.join(chunk::TMP_DIR))?)?
+                .unwrap_or_else(|| FileTree::build(remote))
+        };
+        // from now on it's mapped rather than held in memory
+        tree.save(&path, remote_dir)?;
+        FileTree::load(&path, Some(remote_dir))
+    }
+
+    /// the index built on the remote with -c, if it has one, copied to tmp first and deleted once
+    /// it's loaded
+    pub fn load_remote(remote: &dyn RemoteBackend, tmp: &Path) -> SerdeResult<Option<Self>> {
+        for (name, version) in [(INDEX_NAME, None)]
+            .into_iter()
+            .chain(LEGACY.iter().map(|(name, version)| (*name, Some(*version))))
+        {
+            let loaded = match fetch_index(remote, Path::new(name), tmp) {
+                Ok(false) => continue,
+                Ok(true) => match version {
+                    None => FileTree::load(tmp, None),
+                    Some(version) => load_legacy(tmp, version),
+                },
+                Err(e) => Err(e),
+            };
+            // a mapped one stays readable until it's dropped
+            std::fs::remove_file(tmp).ok();
+            return Ok(Some(
+                loaded.map_err(|e| format!("{name} on the remote: {e}"))?,
+            ));
+        }
+        Ok(None)
+    }
+
+    /// load an index, checking it was built from remote if given (see remote::identity), the
+    /// current version is mapped rather than read, older versions are upgraded in memory
+    pub fn load(path: &Path, remote: Option<&Path>) -> SerdeResult<Self> {
+        let file = File::open(path)?;
+        let mut reader = BufReader::new(&file);
+        let mut magic = [0; MAGIC.len()];
+        let mut version = [0; 4];
+        if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(IndexError::NotAnIndex(path.to_path_buf()).into());
+        }
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version > VERSION {
+            return Err(IndexError::TooNew {
+                path: path.to_path_buf(),
+                version,
+            }
+            .into());
+        }
+
+        let corrupt = |error| IndexError::Corrupt {
+            path: path.to_path_buf(),
+            version,
+            error,
+        };
+        let (built_from, tree) = if version >= 4 {
+            let index = Mapped::open(&file, version).map_err(corrupt)?;
+            let built_from = index
+                .string(index.layout.remote)
+                .map(PathBuf::from)
+                .ok_or_else(|| corrupt("remote_dir out of bounds".into()))?;
+            let mut tree = FileTree {
+                index: Some(index),
+                ..Default::default()
+            };
+            if version < VERSION {
+                warn!("upgrading index {:?} from version {version}", path);
+                tree.decode_all();
+            }
+            (built_from, tree)
+        } else {
+            warn!("upgrading index {:?} from version {version}", path);
+            let (header, tree) = upgrade(version, reader).map_err(corrupt)?;
+            (header.remote, tree)
+        };
+        if let Some(expected) = remote {
+            if remote::identity(&built_from) != remote::identity(expected) {
+                return Err(IndexError::WrongRemote {
+                    path: path.to_path_buf(),
+                    remote: built_from,
+                    expected: expected.to_path_buf(),
+                }
+                .into());
+            }
+        }
+        Ok(tree)
+    }
+
+    /// save as the current version via a temporary file, so a crash never leaves half an index
+    /// and a mapped one is never changed underneath its mapping
+    pub fn save(&self, path: &Path, remote: &Path) -> SerdeResult<()> {
+        let mut records = Vec::new();
+        let mut children = Vec::new();
+        let mut links = Vec::new();
+        let mut strings = Vec::new();
+        let (mut child_count, mut link_count) = (0, 0);
+        let mut record_size = None;
+        let inos = self.inos();
+        for &ino in &inos {
+            let file = self.get(ino).ok_or("entry missing from the tree")?;
+            let name = file.path.file_name().unwrap_or_default();
+            let extra = match &file.type_extra {
+                TypeExtra::Directory(dir) => {
+                    let mut dir: Vec<_> = dir.iter().collect();
+                    dir.sort_unstable_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+                    for (name, ino) in &dir {
+                        let name = push_string(&mut strings, name);
+                        bincode::serialize_into(&mut children, &Child { name, ino: **ino })?;
+                    }
+                    child_count += dir.len() as u64;
+                    Span {
+                        start: child_count - dir.len() as u64,
+                        len: dir.len() as u64,
+                    }
+                }
+                TypeExtra::Symlink(target) => push_string(&mut strings, target),
+                TypeExtra::RegularFile => {
+                    for (parent, path) in &file.links {
+                        let name = push_string(&mut strings, path.file_name().unwrap_or_default());
+                        bincode::serialize_into(
+                            &mut links,
+                            &Link {
+                                parent: *parent,
+                                name,
+                            },
+                        )?;
+                    }
+                    link_count += file.links.len() as u64;
+                    Span {
+                        start: link_count - file.links.len() as u64,
+                        len: file.links.len() as u64,
+                    }
+                }
+                TypeExtra::Special => Span::default(),
+            };
+            let record = Record {
+                ino,
+                parent: file.parent,
+                name: push_string(&mut strings, name),
+                attr: file.attr,
+                extra,
+                hash: file.hash.unwrap_or_default(),
+            };
+            let before = records.len();
+            bincode::serialize_into(&mut records, &record)?;
+            let size = (records.len() - before) as u64;
+            if *record_size.get_or_insert(size) != size {
+                return Err("records differ in size, programming error".into());
+            }
+        }
+
+        let remote = push_string(&mut strings, remote.as_os_str());
+        let mut layout = Layout {
+            remote,
+            max_ino: self.max_ino(),
+            record_size: record_size.unwrap_or(8),
+            records: Span {
+                start: 0,
+                len: inos.len() as u64,
+            },
+            children: Span {
+                start: 0,
+                len: child_count,
+            },
+            links: Span {
+                start: 0,
+                len: link_count,
+            },
+            strings: Span {
+                start: 0,
+                len: strings.len() as u64,
+            },
+        };
+        layout.records.start = (MAGIC.len() + 4) as u64 + bincode::serialized_size(&layout)?;
+        layout.children.start = layout.records.start + records.len() as u64;
+        layout.links.start = layout.children.start + children.len() as u64;
+        layout.strings.start = layout.links.start + links.len() as u64;
+
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        {
+            let mut file = BufWriter::new(File::create(&tmp)?);
+            file.write_all(MAGIC)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+            bincode::serialize_into(&mut file, &layout)?;
+            for section in [&records, &children, &links, &strings] {
+                file.write_all(section)?;
+            }
+            file.flush()?;
+        }
+        Ok(std::fs::rename(tmp, path)?)
+    }
+
+    /// ino's entry, decoded from the index unless it's been changed since it was loaded
+    pub(crate) fn get(&self, ino: u64) -> Option<Cow<'_, FileInfo>> {
+        if let Some(file) = self.inode_to_path.get(&ino) {
+            return Some(Cow::Borrowed(file));
+        }
+        if self.removed.contains(&ino) {
+            return None;
+        }
+        let index = self.index.as_ref()?;
+        let record = index.record(ino)?;
+        let (type_extra, links) = match record.attr.kind {
+            FileType::Directory => {
+                let children = (0..record.extra.len)
+                    .map(|i| {
+                        let (name, ino) = index.child_at(&record, i)?;
+                        Some((name.to_os_string(), ino))
+                    })
+                    .collect::<Option<_>>()?;
+                (TypeExtra::Directory(children), Vec::new())
+            }
+            FileType::Symlink => {
+                let target = index.string(record.extra)?.to_os_string();
+                (TypeExtra::Symlink(target), Vec::new())
+            }
+            FileType::RegularFile => {
+                let links = index
+                    .links(&record)?
+                    .into_iter()
+                    .map(|(parent, name)| Some((parent, self.path(parent)?.join(name))))
+                    .collect::<Option<_>>()?;
+                (TypeExtra::RegularFile, links)
+            }
+            _ => (TypeExtra::Special, Vec::new()),
+        };
+        Some(Cow::Owned(FileInfo {
+            parent: record.parent,
+            path: self.path(ino)?,
+            attr: record.attr,
+            type_extra,
+            links,
+            hash: Some(record.hash).filter(|hash| *hash != ContentHash::default()),
+        }))
+    }
+
+    /// ino's entry to change, copied out of the index first
+    pub(crate) fn get_mut(&mut self, ino: u64) -> Option<&mut FileInfo> {
+        if !self.inode_to_path.contains_key(&ino) {
+            let file = self.get(ino)?.into_owned();
+            self.inode_to_path.insert(ino, file);
+        }
+        self.inode_to_path.get_mut(&ino)
+    }
+
+    pub(crate) fn insert(&mut self, ino: u64, file: FileInfo) {
+        self.removed.remove(&ino);
+        self.inode_to_path.insert(ino, file);
+    }
+
+    pub(crate) fn remove(&mut self, ino: u64) -> Option<FileInfo> {
+        let file = self.get(ino)?.into_owned();
+        self.inode_to_path.remove(&ino);
+        if self.index.is_some() {
+            self.removed.insert(ino);
+        }
+        Some(file)
+    }
+
+    /// just the attr of ino, without decoding the rest of it
+    pub(crate) fn attr(&self, ino: u64) -> Option<FileAttr> {
+        match self.inode_to_path.get(&ino) {
+            Some(file) => Some(file.attr),
+            None if self.removed.contains(&ino) => None,
+            None => Some(self.index.as_ref()?.record(ino)?.attr),
+        }
+    }
+
+    pub(crate) fn parent(&self, ino: u64) -> Option<u64> {
+        match self.inode_to_path.get(&ino) {
+            Some(file) => Some(file.parent),
+            None if self.removed.contains(&ino) => None,
+            None => Some(self.index.as_ref()?.record(ino)?.parent),
+        }
+    }
+
+    /// the inode of name in directory parent
+    pub(crate) fn child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        match self.inode_to_path.get(&parent) {
+            Some(FileInfo {
+                type_extra: TypeExtra::Directory(children),
+                ..
+            }) => children.get(name).copied(),
+            Some(_) => None,
+            None if self.removed.contains(&parent) => None,
+            None => {
+                let index = self.index.as_ref()?;
+                let dir = index.record(parent)?;
+                if dir.attr.kind != FileType::Directory {
+                    return None;
+                }
+                index.child(&dir, name)
+            }
+        }
+    }
+
+    /// the children of directory ino from the offset'th on, a directory still in the index is
+    /// read straight from it, so listing a huge one a page at a time doesn't decode all of it
+    pub(crate) fn children(
+        &self,
+        ino: u64,
+        offset: usize,
+    ) -> Option<Box<dyn Iterator<Item = (OsString, u64)> + '_>> {
+        match self.inode_to_path.get(&ino) {
+            Some(FileInfo {
+                type_extra: TypeExtra::Directory(children),
+                ..
+            }) => Some(Box::new(
+                children
+                    .iter()
+                    .skip(offset)
+                    .map(|(name, ino)| (name.clone(), *ino)),
+            )),
+            Some(_) => None,
+            None if self.removed.contains(&ino) => None,
+            None => {
+                let index = self.index.as_ref()?;
+                let dir = index.record(ino)?;
+                if dir.attr.kind != FileType::Directory {
+                    return None;
+                }
+                Some(Box::new((offset as u64..dir.extra.len).map_while(
+                    move |i| {
+                        let (name, ino) = index.child_at(&dir, i)?;
+                        Some((name.to_os_string(), ino))
+                    },
+                )))
+            }
+        }
+    }
+
+    /// path of ino relative to the root, rebuilt from its name and the names of its parents
+    fn path(&self, mut ino: u64) -> Option<PathBuf> {
+        let index = self.index.as_ref();
+        let mut names = Vec::new();
+        let mut path = loop {
+            if let Some(file) = self.inode_to_path.get(&ino) {
+                break file.path.clone();
+            }
+            if ino == 1 {
+                break PathBuf::new();
+            }
+            let index = index?;
+            // a corrupt index could have a loop of parents
+            if names.len() as u64 > index.layout.records.len {
+                return None;
+            }
+            let record = index.record(ino)?;
+            names.push(record.name);
+            ino = record.parent;
+        };
+        for name in names.into_iter().rev() {
+            path.push(index?.string(name)?);
+        }
+        Some(path)
+    }
+
+    /// every inode in the tree, in order
+    pub(crate) fn inos(&self) -> Vec<u64> {
+        let mut inos: Vec<_> = self
+            .index
+            .iter()
+            .flat_map(|index| index.inos())
+            .filter(|ino| !self.removed.contains(ino) && !self.inode_to_path.contains_key(ino))
+            .chain(self.inode_to_path.keys().copied())
+            .collect();
+        inos.sort_unstable();
+        inos
+    }
+
+    /// any index for inspecting it, path can also be a cache dir, nothing is checked or migrated
+    pub fn open(path: &Path) -> SerdeResult<Self> {
+        if path.is_dir() {
+            return FileTree::open(&path.join(INDEX_NAME));
+        }
+        let legacy = LEGACY
+            .iter()
+            .find(|(name, _)| path.file_name() == Some(OsStr::new(name)));
+        match legacy {
+            Some((_, version)) => load_legacy(path, *version),
+            None => FileTree::load(path, None),
+        }
+    }
+
+    /// the remote_dir the index this was loaded from was built from
+    pub fn remote(&self) -> Option<PathBuf> {
+        let index = self.index.as_ref()?;
+        Some(PathBuf::from(index.string(index.layout.remote)?))
+    }
+
+    /// decode every entry out of the index, for when all of it is needed anyway like merging it
+    /// into another tree
+    pub fn decode_all(&mut self) {
+        for ino in self.inos() {
+            self.get_mut(ino);
+        }
+        self.index = None;
+        self.removed.clear();
+    }
+
+    /// the highest inode ever in the tree, removed ones aren't reused while mounted
+    pub(crate) fn max_ino(&self) -> u64 {
+        let mapped = self.index.as_ref().map_or(0, |index| index.layout.max_ino);
+        let changed = self.inode_to_path.keys().max().copied().unwrap_or(0);
+        mapped.max(changed).max(1)
+    }
+}
+
+/// read everything after the magic and version of a compressed index of version, upgrading it
+/// to the current FileTree
+fn upgrade(version: u32, file: impl Read) -> SerdeResult<(Header, FileTree)> {
+    let mut file = zstd::stream::Decoder::new(file)?;
+    match version {
+        // the whole tree compressed, otherwise the same as version 2
+        3 => {
+            let header = bincode::deserialize_from(&mut file)?;
+            Ok((header, bincode::deserialize_from(file)?))
+        }
+        // every version before the header is legacy
+        version => Err(format!("unknown index version {version}").into()),
+    }
+}
+
+/// copy the file at path on the remote to local, returns false if the remote has no such file
+fn fetch_index(remote: &dyn RemoteBackend, path: &Path, local: &Path) -> SerdeResult<bool> {
+    let file = match remote.open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    info!("copying index {:?} from the remote", path);
+    let mut out = File::create(local)?;
+    // a request per read for some remotes, so make them big ones
+    let mut reader =
+        BufReader::with_capacity(chunk::CHUNK_SIZE as usize, remote::Reader::new(file));
+    std::io::copy(&mut reader, &mut out)?;
+    Ok(true)
+}
+
+/// a headerless index from before versioning, version is known from its name
+fn load_legacy(path: &Path, version: u32) -> SerdeResult<FileTree> {
+    warn!("upgrading index {:?} from version {version}", path);
+    let file = zstd::stream::Decoder::new(BufReader::new(File::open(path)?))?;
+    let tree = match version {
+        1 => bincode::deserialize_from::<_, v1::FileTree>(file).map(FileTree::from),
+        2 => bincode::deserialize_from(file),
+        _ => unreachable!("not a legacy version"),
+    };
+    tree.map_err(|error| {
+        IndexError::Corrupt {
+            path: path.to_path_buf(),
+            version,
+            error,
+        }
+        .into()
+    })
+}
+
+/// before hard links were tracked
+mod v1 {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct FileInfo {
+        pub parent: u64,
+        pub path: PathBuf,
+        #[serde(with = "FileAttrDef")]
+        pub attr: FileAttr,
+        pub type_extra: TypeExtra,
+    }
+
+    #[derive(Deserialize)]
+    pub struct FileTree {
+        pub inode_to_path: HashMap<u64, FileInfo>,
+    }
+}
+
+/// before hashes, otherwise the same as the current version
+mod v4 {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct Record {
+        pub ino: u64,
+        pub parent: u64,
+        pub name: Span,
+        #[serde(with = "FileAttrDef")]
+        pub attr: FileAttr,
+        pub extra: Span,
+    }
+}
+
+impl From<v4::Record> for Record {
+    fn from(record: v4::Record) -> Self {
+        Record {
+            ino: record.ino,
+            parent: record.parent,
+            name: record.name,
+            attr: record.attr,
+            extra: record.extra,
+            hash: ContentHash::default(),
+        }
+    }
+}
+
+impl From<v1::FileTree> for FileTree {
+    fn from(tree: v1::FileTree) -> Self {
+        let inode_to_path = tree
+            .inode_to_path
+            .into_iter()
+            .map(|(ino, file)| {
+                let file = FileInfo {
+                    parent: file.parent,
+                    path: file.path,
+                    attr: file.attr,
+                    type_extra: file.type_extra,
+                    links: Vec::new(),
+                    hash: None,
+                };
+                (ino, file)
+            })
+            .collect();
+        let mut tree = FileTree {
+            inode_to_path,
+            ..Default::default()
+        };
+        // hard links were separate files, until the next refresh links them
+        tree.count_links();
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::Local;
+
+    #[test]
+    fn remote_spelled_differently() {
+        let dir = std::env::temp_dir().join(format!("cache-fs-index-test-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let remote = dir.join("roms");
+        std::fs::create_dir_all(remote.join("snes")).unwrap();
+        std::fs::write(remote.join("snes/game.sfc"), b"rom").unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&remote, &link).unwrap();
+        let path = dir.join(INDEX_NAME);
+        FileTree::build(&Local::new(remote.clone()))
+            .save(&path, &remote.join(""))
+            .unwrap();
+
+        for same in [
+            remote.clone(),
+            remote.join(""),
+            dir.join("./roms//"),
+            remote.join("snes/.."),
+            link,
+        ] {
+            let tree = FileTree::load(&path, Some(&same));
+            assert!(tree.is_ok(), "{:?}: {:?}", same, tree.err());
+        }
+        let other = FileTree::load(&path, Some(&remote.join("snes")));
+        assert!(matches!(
+            other
+                .err()
+                .and_then(|e| e.downcast::<IndexError>().ok())
+                .as_deref(),
+            Some(IndexError::WrongRemote { .. })
+        ));
+
+        FileTree::default()
+            .save(&path, Path::new("HTTP://server/roms/"))
+            .unwrap();
+        assert!(FileTree::load(&path, Some(Path::new("http://server/roms"))).is_ok());
+        assert!(FileTree::load(&path, Some(Path::new("http://server/other"))).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,276 @@
+use crate::{evict, remote, FileInfo, FileTree, TreeChange, TypeExtra};
+use fuser::FileType;
+use serde_json::json;
+use std::{
+    collections::{BinaryHeap, HashSet},
+    ffi::OsString,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+// how many directories stats lists
+const LARGEST_DIRS: usize = 10;
+
+/// `cache-fs index dump|stats [-o json] INDEX` prints every entry or a summary of an index
+///
+/// `cache-fs index diff [-o json,remote_dir=/remote/dir] INDEX [OTHER_INDEX]` prints what merging
+/// OTHER_INDEX, or a fresh scan of remote_dir, into INDEX would change, exits 1 if anything would
+///
+/// INDEX can be an index file or a cache dir
+pub fn command(mut args: impl Iterator<Item = OsString>) {
+    let cmd = args
+        .next()
+        .expect("missing index command, one of dump, stats or diff");
+    let mut json = false;
+    let mut remote_dir = None;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg != "-o" {
+            paths.push(PathBuf::from(arg));
+            continue;
+        }
+        let opts = args.next().expect("found -o but missing opts");
+        for opt in opts.to_str().expect("non-utf8 opts").split(',') {
+            if opt == "json" {
+                json = true;
+            } else if let Some(dir) = opt.strip_prefix("remote_dir=") {
+                remote_dir = Some(PathBuf::from(dir));
+            } else {
+                panic!("unknown index option {opt}");
+            }
+        }
+    }
+    let mut paths = paths.into_iter();
+    let path = paths.next().expect("missing index");
+    let tree = open(&path);
+
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let ret = match cmd.to_str() {
+        Some("dump") => dump(&tree, json, &mut out),
+        Some("stats") => stats(&tree, json, &mut out),
+        Some("diff") => {
+            let other = match (remote_dir, paths.next()) {
+                (Some(remote_dir), None) => FileTree::scan(remote::open(&remote_dir).as_ref())
+                    .expect("cannot scan remote_dir"),
+                (None, Some(other)) => open(&other),
+                _ => panic!("diff needs either remote_dir= or a second index"),
+            };
+            match diff(tree, other, json, &mut out) {
+                Ok(true) => Ok(()),
+                Ok(false) => {
+                    out.flush().ok();
+                    std::process::exit(1);
+                }
+                Err(e) => Err(e),
+            }
+        }
+        _ => panic!("unknown index command {:?}", cmd),
+    };
+    ret.and_then(|_| out.flush())
+        .expect("cannot write to stdout");
+}
+
+fn open(path: &Path) -> FileTree {
+    FileTree::open(path).unwrap_or_else(|e| panic!("cannot open index {:?}: {e}", path))
+}
+
+fn kind(kind: FileType) -> &'static str {
+    match kind {
+        FileType::NamedPipe => "fifo",
+        FileType::CharDevice => "char",
+        FileType::BlockDevice => "block",
+        FileType::Directory => "dir",
+        FileType::RegularFile => "file",
+        FileType::Symlink => "symlink",
+        FileType::Socket => "socket",
+    }
+}
+
+/// every name in the tree depth first in name order, with the path it's at rather than the
+/// primary one, so each name of a hard linked file is visited
+fn walk(tree: &FileTree, mut f: impl FnMut(&Path, &FileInfo)) {
+    let mut stack = vec![(PathBuf::new(), 1)];
+    while let Some((path, ino)) = stack.pop() {
+        let file = match tree.file(ino) {
+            Some(file) => file,
+            None => continue,
+        };
+        f(&path, &file);
+        if let TypeExtra::Directory(children) = &file.type_extra {
+            let mut children: Vec<_> = children.iter().collect();
+            // reversed so they come off the stack in order
+            children.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
+            stack.extend(
+                children
+                    .into_iter()
+                    .map(|(name, ino)| (path.join(name), *ino)),
+            );
+        }
+    }
+}
+
+fn dump(tree: &FileTree, json: bool, out: &mut impl Write) -> std::io::Result<()> {
+    let mut ret = Ok(());
+    walk(tree, |path, file| {
+        if ret.is_err() {
+            return;
+        }
+        let attr = &file.attr;
+        let mtime = attr
+            .mtime
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let target = match &file.type_extra {
+            TypeExtra::Symlink(target) => Some(target),
+            _ => None,
+        };
+        ret = if json {
+            let line = json!({
+                "ino": attr.ino,
+                "parent": file.parent,
+                "path": path.to_string_lossy(),
+                "kind": kind(attr.kind),
+                "size": attr.size,
+                "mtime": mtime,
+                "perm": attr.perm & 0o7777,
+                "uid": attr.uid,
+                "gid": attr.gid,
+                "nlink": attr.nlink,
+                "target": target.map(|t| t.to_string_lossy()),
+                "hash": file.hash.map(|hash| blake3::Hash::from(hash).to_hex().to_string()),
+            });
+            writeln!(out, "{line}")
+        } else {
+            write!(
+                out,
+                "{:>8} {:<7} {:04o} {:>12} {:>10} /{}",
+                attr.ino,
+                kind(attr.kind),
+                attr.perm & 0o7777,
+                attr.size,
+                mtime,
+                path.display()
+            )
+            .and_then(|_| match target {
+                Some(target) => writeln!(out, " -> {}", Path::new(target).display()),
+                None => writeln!(out),
+            })
+        };
+    });
+    ret
+}
+
+fn stats(tree: &FileTree, json: bool, out: &mut impl Write) -> std::io::Result<()> {
+    let (mut files, mut dirs, mut symlinks, mut special, mut hard_linked) = (0, 0, 0, 0, 0);
+    let mut names = 0;
+    let mut size = 0;
+    let mut seen = HashSet::new();
+    // total size under every directory, smallest first so the largest are kept
+    let mut largest = BinaryHeap::new();
+    // (path, size so far) of the directories above the one being walked
+    let mut above: Vec<(PathBuf, u64)> = Vec::new();
+    let mut finish = |above: &mut Vec<(PathBuf, u64)>| {
+        let (path, dir_size) = above.pop().expect("walked out of the root");
+        if let Some((_, parent_size)) = above.last_mut() {
+            *parent_size += dir_size;
+            largest.push(std::cmp::Reverse((dir_size, path)));
+            if largest.len() > LARGEST_DIRS {
+                largest.pop();
+            }
+        }
+    };
+    walk(tree, |path, file| {
+        while above.last().is_some_and(|(dir, _)| !path.starts_with(dir)) {
+            finish(&mut above);
+        }
+        names += 1;
+        match file.attr.kind {
+            FileType::Directory => {
+                dirs += 1;
+                above.push((path.to_path_buf(), 0));
+            }
+            FileType::RegularFile => {
+                if !seen.insert(file.attr.ino) {
+                    return;
+                }
+                files += 1;
+                size += file.attr.size;
+                if !file.links.is_empty() {
+                    hard_linked += 1;
+                }
+                if let Some((_, dir_size)) = above.last_mut() {
+                    *dir_size += file.attr.size;
+                }
+            }
+            FileType::Symlink => symlinks += 1,
+            _ => special += 1,
+        }
+    });
+    while !above.is_empty() {
+        finish(&mut above);
+    }
+    let mut largest: Vec<_> = largest.into_iter().map(|r| r.0).collect();
+    largest.sort_unstable_by(|a, b| b.cmp(a));
+
+    let remote = tree.remote();
+    if json {
+        let largest: Vec<_> = largest
+            .iter()
+            .map(|(size, path)| json!({ "path": path.to_string_lossy(), "size": size }))
+            .collect();
+        let stats = json!({
+            "remote_dir": remote.map(|r| r.to_string_lossy().into_owned()),
+            "names": names,
+            "files": files,
+            "hard_linked_files": hard_linked,
+            "dirs": dirs,
+            "symlinks": symlinks,
+            "special": special,
+            "size": size,
+            "largest_dirs": largest,
+        });
+        return writeln!(out, "{stats}");
+    }
+    if let Some(remote) = remote {
+        writeln!(out, "remote_dir:  {}", remote.display())?;
+    }
+    writeln!(out, "names:       {names}")?;
+    writeln!(out, "files:       {files} ({hard_linked} hard linked)")?;
+    writeln!(out, "directories: {dirs}")?;
+    writeln!(out, "symlinks:    {symlinks}")?;
+    writeln!(out, "special:     {special}")?;
+    writeln!(out, "total size:  {} ({size} bytes)", evict::human(size))?;
+    writeln!(out, "largest directories:")?;
+    for (size, path) in largest {
+        writeln!(out, "  {:>8}  /{}", evict::human(size), path.display())?;
+    }
+    Ok(())
+}
+
+/// print what merging other into tree would change, returns true if nothing would
+fn diff(
+    mut tree: FileTree,
+    mut other: FileTree,
+    json: bool,
+    out: &mut impl Write,
+) -> std::io::Result<bool> {
+    // merging takes entries out of other, which it can't do to a mapped index
+    other.decode_all();
+    let changes = tree.merge(other);
+    for change in &changes {
+        let (sign, change, path) = match change {
+            TreeChange::Added { path, .. } => ('+', "added", path),
+            TreeChange::Removed { path, .. } => ('-', "removed", path),
+            TreeChange::Changed { path, .. } => ('~', "changed", path),
+        };
+        if json {
+            let line = json!({ "change": change, "path": path.to_string_lossy() });
+            writeln!(out, "{line}")?;
+        } else {
+            writeln!(out, "{sign} /{}", path.display())?;
+        }
+    }
+    Ok(changes.is_empty())
+}
@@ -1,35 +1,65 @@
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, Request,
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyXattr, Request,
 };
 use libc::{
-    c_int, exit, fork, setsid, EINVAL, EIO, ENOENT, EPERM, O_ACCMODE, O_APPEND, O_CREAT, O_EXCL,
-    O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY,
+    c_int, exit, fork, setsid, EHOSTDOWN, EINVAL, EIO, ENODATA, ENOENT, ENOTSUP, ENXIO, EPERM,
+    ERANGE, EROFS, O_ACCMODE, O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY,
 };
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
+use remote::RemoteBackend;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap, HashSet},
     env,
     ffi::{OsStr, OsString},
     fmt::{Debug, Formatter},
     fs::File,
-    io::{BufReader, BufWriter, Error, ErrorKind},
+    io::{Error, ErrorKind},
     ops::Deref,
     os::unix::{
         ffi::OsStrExt,
-        fs::{MetadataExt, PermissionsExt},
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
     },
     path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+mod chunk;
+mod compress;
+mod evict;
+mod failover;
+mod health;
+mod http;
+mod index;
+mod inspect;
+mod notify;
+mod pin;
+mod pool;
+mod remote;
+mod rescan;
+mod s3;
+#[cfg(feature = "sftp")]
+mod sftp;
+mod store;
+mod verify;
+mod warm;
+
 type Result<T> = std::result::Result<T, Error>;
 type SerdeResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-// must change this if any of the structs change
-const INDEX_NAME: &str = "cache-fs.tree.zst";
 const TTL: Duration = Duration::from_secs(120);
+const XATTR_PINNED: &str = "user.cachefs.pinned";
+// read only, and only on regular files except remote_path
+const XATTR_CACHED: &str = "user.cachefs.cached";
+const XATTR_CACHED_BYTES: &str = "user.cachefs.cached_bytes";
+const XATTR_REMOTE_PATH: &str = "user.cachefs.remote_path";
+const XATTR_LAST_ACCESS: &str = "user.cachefs.last_access";
+const DEFAULT_PARTIAL_MIN_SIZE: u64 = 64 << 20;
+const DEFAULT_READAHEAD: u64 = 4 << 20;
 
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "FileType")]
@@ -64,34 +94,82 @@ struct FileAttrDef {
     pub blksize: u32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 enum TypeExtra {
     RegularFile,
     Symlink(OsString),
     Directory(HashMap<OsString, u64>),
+    // fifos, sockets and device nodes, everything about them is in the attr
+    Special,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct FileInfo {
     parent: u64,
+    // also where it's cached, for hard linked files that's the first name found
     path: PathBuf,
     #[serde(with = "FileAttrDef")]
     attr: FileAttr,
     type_extra: TypeExtra,
+    // every other (parent, path) a hard linked file is found under
+    links: Vec<(u64, PathBuf)>,
+    // of the contents of a regular file, only for indexes built with -c, which older index
+    // versions didn't have room for
+    #[serde(skip)]
+    hash: Option<chunk::ContentHash>,
 }
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Default, Deserialize)]
 struct FileTree {
+    // every entry of a fresh scan, or those added or changed since the index was loaded
     inode_to_path: HashMap<u64, FileInfo>,
+    // entries of the index removed since it was loaded
+    #[serde(skip)]
+    removed: HashSet<u64>,
+    // the index this was loaded from, anything not in inode_to_path is looked up in it
+    #[serde(skip)]
+    index: Option<index::Mapped>,
+    // directories we failed to read while building, only meaningful for a fresh scan
+    #[serde(skip)]
+    unreadable: HashSet<u64>,
+}
+
+/// whether a rescan has anything to update in an entry, scanning reads every directory which
+/// moves their atime on the remote, so that alone doesn't count
+fn same_attr(old: &FileAttr, new: &FileAttr) -> bool {
+    FileAttr {
+        atime: old.atime,
+        ..*new
+    } == *old
+}
+
+/// one difference applied to a tree by FileTree::merge
+#[derive(Debug)]
+enum TreeChange {
+    Added {
+        parent: u64,
+        name: OsString,
+        path: PathBuf,
+    },
+    Removed {
+        parent: u64,
+        name: OsString,
+        path: PathBuf,
+    },
+    Changed {
+        ino: u64,
+        path: PathBuf,
+    },
 }
 
 impl Debug for FileTree {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "FileTree")?;
-        // dumb way to always print this in inode order
-        let mut inode_to_path = std::collections::BTreeMap::default();
-        inode_to_path.extend(self.inode_to_path.iter());
-        for (key, val) in inode_to_path.iter() {
+        for key in self.inos() {
+            let val = match self.get(key) {
+                Some(val) => val,
+                None => continue,
+            };
             writeln!(
                 f,
                 "-- {key}: [parent: {}, {:?}, {:?}]",
@@ -100,7 +178,10 @@ impl Debug for FileTree {
             match &val.type_extra {
                 TypeExtra::Directory(children) => writeln!(f, "---- children: {:?}", children)?,
                 TypeExtra::Symlink(link) => writeln!(f, "---- link to: {:?}", link)?,
-                TypeExtra::RegularFile => (),
+                TypeExtra::RegularFile | TypeExtra::Special => (),
+            }
+            if !val.links.is_empty() {
+                writeln!(f, "---- hard links: {:?}", val.links)?;
             }
         }
         Ok(())
@@ -108,178 +189,541 @@ impl Debug for FileTree {
 }
 
 impl FileTree {
-    fn load_or_build(root_path: &Path, cache_path: &Path) -> SerdeResult<Self> {
-        let path = cache_path.join(INDEX_NAME);
-        match FileTree::load(&path) {
-            Ok(tree) => return Ok(tree),
-            Err(e) => warn!("error loading {:?}: {:?}", path, e),
-        }
-        let root_index = root_path.join(INDEX_NAME);
-        if root_index.exists() {
-            std::fs::copy(&root_index, &path)?;
-            match FileTree::load(&path) {
-                Ok(tree) => return Ok(tree),
-                Err(e) => warn!("error loading {:?}: {:?}", path, e),
-            }
-        }
-        let tree = FileTree::build(root_path);
-        tree.save(&path)?;
-        Ok(tree)
+    fn build(remote: &dyn RemoteBackend) -> Self {
+        FileTree::scan(remote).expect("cannot read root dir")
     }
 
-    fn load(path: &Path) -> SerdeResult<Self> {
-        let file = File::open(path)?;
-        let file = BufReader::new(file);
-        let file = zstd::stream::Decoder::new(file)?;
-
-        Ok(bincode::deserialize_from(file)?)
-    }
-
-    fn save(&self, path: &Path) -> SerdeResult<()> {
-        let file = File::create(path)?;
-        let file = BufWriter::new(file);
-        let file = zstd::stream::Encoder::new(file, 9)?.auto_finish();
-
-        Ok(bincode::serialize_into(file, self)?)
-    }
-
-    fn build(root_path: &Path) -> Self {
+    fn scan(remote: &dyn RemoteBackend) -> Result<Self> {
         let mut tree = FileTree::default();
 
         let mut ino = 1;
         let root = FileInfo {
             parent: 0, // probably should be None but this is the only file without a parent
             path: PathBuf::new(),
-            attr: std::fs::symlink_metadata(root_path)
-                .and_then(|m| meta2attr(&m, ino))
-                .expect("cannot read root dir"),
+            attr: FileAttr {
+                ino,
+                ..remote.stat(Path::new(""))?.attr
+            },
             type_extra: TypeExtra::Directory(Default::default()),
+            links: Vec::new(),
+            hash: None,
         };
         tree.inode_to_path.insert(1, root);
         ino += 1;
 
+        // remote link_id of hard linked files -> our inode
+        let mut hard_links = HashMap::new();
         let mut dirs = vec![1];
         while !dirs.is_empty() {
             let mut all_dirs = Vec::new();
             for dir in dirs {
-                tree.process_dir(root_path, &mut ino, &mut all_dirs, dir);
+                tree.process_dir(remote, &mut ino, &mut all_dirs, &mut hard_links, dir);
             }
             dirs = all_dirs;
         }
+        tree.count_links();
 
         debug!("build tree: {:?}", tree);
-        tree
+        Ok(tree)
+    }
+
+    /// hash the contents of every regular file of a fresh scan, reusing the hash from previous of
+    /// any file at the same path with the same size and mtime, returns how many files were hashed,
+    /// reused and unreadable
+    fn hash_files(
+        &mut self,
+        remote: &dyn RemoteBackend,
+        previous: Option<&FileTree>,
+    ) -> (usize, usize, usize) {
+        let (mut hashed, mut reused, mut failed) = (0, 0, 0);
+        for file in self.inode_to_path.values_mut() {
+            if file.attr.kind != FileType::RegularFile {
+                continue;
+            }
+            let known = previous
+                .and_then(|previous| previous.file(previous.resolve(&file.path)?))
+                .filter(|old| {
+                    old.attr.kind == FileType::RegularFile
+                        && old.attr.size == file.attr.size
+                        && old.attr.mtime == file.attr.mtime
+                })
+                .and_then(|old| old.hash);
+            file.hash = match known {
+                Some(hash) => {
+                    reused += 1;
+                    Some(hash)
+                }
+                None => match remote
+                    .open(&file.path)
+                    .and_then(|f| chunk::hash(remote::Reader::new(f)))
+                {
+                    Ok(hash) => {
+                        hashed += 1;
+                        Some(hash)
+                    }
+                    Err(e) => {
+                        error!("cannot hash {:?}: {:?}", file.path, e);
+                        failed += 1;
+                        None
+                    }
+                },
+            };
+        }
+        (hashed, reused, failed)
+    }
+
+    /// re-scan the remote and merge any differences into this tree, see merge
+    fn refresh(&mut self, remote: &dyn RemoteBackend) -> Result<Vec<TreeChange>> {
+        let scanned = FileTree::scan(remote)?;
+        Ok(self.merge(scanned))
+    }
+
+    /// apply adds/removes/changes from a freshly scanned tree to this one, keeping existing inode
+    /// numbers stable, entries are matched by name and kind, files by size and mtime
+    fn merge(&mut self, mut other: FileTree) -> Vec<TreeChange> {
+        let mut next_ino = self.max_ino() + 1;
+        let mut changes = Vec::new();
+
+        // only entries that differ are copied out of the index, so a rescan that finds nothing
+        // new doesn't end up holding the whole tree in memory
+        if let Some(other_root) = other.inode_to_path.get(&1) {
+            let attr = FileAttr {
+                ino: 1,
+                ..other_root.attr
+            };
+            if self.attr(1).is_some_and(|root| !same_attr(&root, &attr)) {
+                if let Some(root) = self.get_mut(1) {
+                    root.attr = attr;
+                }
+            }
+        }
+
+        // other inode -> ours, so more names for a hard linked file all end up at the same inode
+        let mut matched = HashMap::new();
+        let mut claimed = HashMap::new();
+        // added after everything is matched, so new names for existing files are linked to them
+        let mut grafts = Vec::new();
+        let mut dirs = vec![(1, 1)];
+        while let Some((ino, other_ino)) = dirs.pop() {
+            if other.unreadable.contains(&other_ino) {
+                // couldn't read it this time, keep what we had rather than deleting everything
+                continue;
+            }
+            let (old_children, new_children) = match (self.folder(ino), other.folder(other_ino)) {
+                (Some(old), Some(new)) => (old.into_owned(), new.into_owned()),
+                _ => continue,
+            };
+            for name in old_children.keys() {
+                if !new_children.contains_key(name) {
+                    self.remove_child(ino, name, &mut changes);
+                }
+            }
+            for (name, other_child) in new_children {
+                let new = other
+                    .inode_to_path
+                    .get(&other_child)
+                    .expect("missing child ino, programming error");
+                let old_child = old_children.get(&name).copied();
+                let same = old_child
+                    .filter(|child| {
+                        // hard linked on one side but not the other, so not the same file
+                        matched.get(&other_child).is_none_or(|c| c == child)
+                            && claimed.get(child).is_none_or(|o| *o == other_child)
+                    })
+                    .and_then(|child| self.attr(child))
+                    .filter(|old| old.kind == new.attr.kind);
+                match same {
+                    Some(old_attr) => {
+                        let child = old_attr.ino;
+                        matched.insert(other_child, child);
+                        claimed.insert(child, other_child);
+                        let changed = match &new.type_extra {
+                            TypeExtra::Directory(_) => {
+                                dirs.push((child, other_child));
+                                false
+                            }
+                            TypeExtra::Symlink(new_link) => {
+                                self.symlink(child).as_deref() != Some(new_link.as_os_str())
+                            }
+                            // a device node pointing at a different device
+                            TypeExtra::Special => {
+                                old_attr.rdev != new.attr.rdev || old_attr.mtime != new.attr.mtime
+                            }
+                            TypeExtra::RegularFile => {
+                                old_attr.size != new.attr.size || old_attr.mtime != new.attr.mtime
+                            }
+                        };
+                        // scans never hash, so keep ours unless the contents changed
+                        let rehash = (changed || new.hash.is_some())
+                            && self.get(child).is_some_and(|old| old.hash != new.hash);
+                        let attr = FileAttr {
+                            ino: child,
+                            ..new.attr
+                        };
+                        if !changed && !rehash && same_attr(&old_attr, &attr) {
+                            continue;
+                        }
+                        let old = self
+                            .get_mut(child)
+                            .expect("missing matched ino, programming error");
+                        if rehash {
+                            old.hash = new.hash;
+                        }
+                        old.attr = attr;
+                        if changed {
+                            if let TypeExtra::Symlink(new_link) = &new.type_extra {
+                                old.type_extra = TypeExtra::Symlink(new_link.clone());
+                            }
+                            changes.push(TreeChange::Changed {
+                                ino: child,
+                                path: old.path.clone(),
+                            });
+                        }
+                    }
+                    None => {
+                        if old_child.is_some() {
+                            // same name but a different kind or file, replace it entirely
+                            self.remove_child(ino, &name, &mut changes);
+                        }
+                        grafts.push((ino, name, other_child));
+                    }
+                }
+            }
+        }
+
+        for (parent, name, other_child) in grafts {
+            let path = match self.get(parent) {
+                Some(dir) => dir.path.join(&name),
+                None => continue,
+            };
+            let child = self.graft(
+                &mut other,
+                parent,
+                path.clone(),
+                other_child,
+                &mut next_ino,
+                &mut matched,
+            );
+            if let Some(TypeExtra::Directory(children)) =
+                self.get_mut(parent).map(|f| &mut f.type_extra)
+            {
+                children.insert(name.clone(), child);
+            }
+            changes.push(TreeChange::Added { parent, name, path });
+        }
+        self.count_links();
+
+        debug!("merged tree: {:?}", self);
+        changes
+    }
+
+    /// unlink name from parent and drop it and everything under it, hard linked files are only
+    /// dropped along with their last name
+    fn remove_child(&mut self, parent: u64, name: &OsStr, changes: &mut Vec<TreeChange>) {
+        let ino = match self.get_mut(parent).map(|f| &mut f.type_extra) {
+            Some(TypeExtra::Directory(children)) => match children.remove(name) {
+                Some(ino) => ino,
+                None => return,
+            },
+            _ => return,
+        };
+        let path = match self.get(parent) {
+            Some(dir) => dir.path.join(name),
+            None => return,
+        };
+        let mut remove = vec![(parent, ino, path.clone())];
+        while let Some((parent, ino, path)) = remove.pop() {
+            if !self.unlink(parent, ino, &path) {
+                continue;
+            }
+            if let Some(FileInfo {
+                type_extra: TypeExtra::Directory(children),
+                path,
+                ..
+            }) = self.remove(ino)
+            {
+                remove.extend(
+                    children
+                        .into_iter()
+                        .map(|(name, child)| (ino, child, path.join(name))),
+                );
+            }
+        }
+        changes.push(TreeChange::Removed {
+            parent,
+            name: name.to_os_string(),
+            path,
+        });
+    }
+
+    /// forget the name (parent, path) of ino, returns true if it was the last one so ino should be
+    /// dropped entirely
+    fn unlink(&mut self, parent: u64, ino: u64, path: &Path) -> bool {
+        let file = match self.get_mut(ino) {
+            Some(file) => file,
+            None => return false,
+        };
+        if file.links.is_empty() {
+            return true;
+        }
+        if file.parent == parent && file.path == path {
+            // the cached copy goes with this name, it'll be fetched again under the next one
+            let (parent, path) = file.links.remove(0);
+            file.parent = parent;
+            file.path = path;
+        } else {
+            file.links.retain(|(p, l)| !(*p == parent && l == path));
+        }
+        false
+    }
+
+    /// add another name for the hard linked file ino, caller must add it to parent's children
+    fn link(&mut self, parent: u64, ino: u64, path: PathBuf) {
+        if let Some(file) = self.get_mut(ino) {
+            file.links.push((parent, path));
+        }
+    }
+
+    /// nlink of regular files is how many names they have in this tree, not on the remote, only
+    /// entries changed since the index was loaded can have gained or lost one
+    fn count_links(&mut self) {
+        for file in self.inode_to_path.values_mut() {
+            if file.attr.kind == FileType::RegularFile {
+                file.attr.nlink = 1 + file.links.len() as u32;
+            }
+        }
+    }
+
+    /// move other_ino and everything under it from other into this tree under parent with fresh
+    /// inode numbers, or link it if it's another name for a file in grafted (other -> ours),
+    /// returns the new inode, caller must add it to parent's children
+    fn graft(
+        &mut self,
+        other: &mut FileTree,
+        parent: u64,
+        path: PathBuf,
+        other_ino: u64,
+        next_ino: &mut u64,
+        grafted: &mut HashMap<u64, u64>,
+    ) -> u64 {
+        if let Some(&ino) = grafted.get(&other_ino) {
+            self.link(parent, ino, path);
+            return ino;
+        }
+        let ino = *next_ino;
+        *next_ino += 1;
+        grafted.insert(other_ino, ino);
+        let mut file = other
+            .inode_to_path
+            .remove(&other_ino)
+            .expect("missing graft ino, programming error");
+        file.parent = parent;
+        file.attr.ino = ino;
+        file.links.clear();
+        if let TypeExtra::Directory(children) = &mut file.type_extra {
+            for (name, child) in children.iter_mut() {
+                *child = self.graft(other, ino, path.join(name), *child, next_ino, grafted);
+            }
+        }
+        file.path = path;
+        self.insert(ino, file);
+        ino
     }
 
     fn process_dir(
         &mut self,
-        root_path: &Path,
+        remote: &dyn RemoteBackend,
         ino_counter: &mut u64,
         dirs: &mut Vec<u64>,
+        hard_links: &mut HashMap<(u64, u64), u64>,
         ino: u64,
     ) {
         let dir = self
             .inode_to_path
             .get_mut(&ino)
             .expect("missing dir ino, programming error");
-        if let Ok(x) = std::fs::read_dir(root_path.join(&dir.path)) {
+        let read_dir = remote.read_dir(&dir.path);
+        if read_dir.is_err() {
+            self.unreadable.insert(ino);
+        }
+        if let Ok(x) = read_dir {
             if let TypeExtra::Directory(children) = &mut dir.type_extra {
-                children.reserve(x.size_hint().0);
+                children.reserve(x.len());
             } else {
                 panic!("impossible")
             };
             let dir_path = dir.path.clone();
-            for de in x.flatten() {
-                if let Ok(attr) = de.metadata().and_then(|m| meta2attr(&m, *ino_counter)) {
-                    if de.file_name() == INDEX_NAME {
-                        continue; // don't show
-                    }
-                    let path = dir_path.join(de.file_name());
-                    let type_extra = match attr.kind {
-                        FileType::RegularFile => TypeExtra::RegularFile,
-                        FileType::Directory => {
-                            dirs.push(attr.ino);
-                            TypeExtra::Directory(Default::default())
+            for (name, stat) in x {
+                if index::is_index_name(&name) {
+                    continue; // don't show
+                }
+                let attr = FileAttr {
+                    ino: *ino_counter,
+                    ..stat.attr
+                };
+                let path = dir_path.join(&name);
+                if let (FileType::RegularFile, Some(link_id)) = (attr.kind, stat.link_id) {
+                    match hard_links.entry(link_id) {
+                        Entry::Occupied(linked) => {
+                            self.link(ino, *linked.get(), path);
+                            if let Some(TypeExtra::Directory(children)) =
+                                self.inode_to_path.get_mut(&ino).map(|f| &mut f.type_extra)
+                            {
+                                children.insert(name, *linked.get());
+                            }
+                            continue;
+                        }
+                        Entry::Vacant(vacant) => {
+                            vacant.insert(attr.ino);
                         }
-                        FileType::Symlink => {
-                            let entry_path = root_path.join(&path);
-                            match std::fs::read_link(entry_path) {
-                                Err(e) => {
-                                    // I guess on error we just ignore this symlink like it doesn't exist
-                                    error!("bad symlink? {:?}", e);
-                                    continue;
-                                }
-                                Ok(x) => TypeExtra::Symlink(x.into_os_string()),
+                    }
+                }
+                let type_extra = match attr.kind {
+                    FileType::RegularFile => TypeExtra::RegularFile,
+                    FileType::Directory => {
+                        dirs.push(attr.ino);
+                        TypeExtra::Directory(Default::default())
+                    }
+                    FileType::Symlink => {
+                        match remote.read_link(&path) {
+                            Err(e) => {
+                                // I guess on error we just ignore this symlink like it doesn't exist
+                                error!("bad symlink? {:?}", e);
+                                continue;
                             }
+                            Ok(x) => TypeExtra::Symlink(x.into_os_string()),
                         }
-                        _ => panic!("impossible to happen, we filter other types out"),
-                    };
-                    let child = FileInfo {
-                        parent: ino,
-                        path,
-                        attr,
-                        type_extra,
-                    };
-                    // avoid this lookup each time with something better?
-                    if let Some(TypeExtra::Directory(children)) =
-                        &mut self.inode_to_path.get_mut(&ino).map(|f| &mut f.type_extra)
-                    {
-                        children.insert(de.file_name(), child.attr.ino);
-                    } else {
-                        unreachable!("this should be impossible");
                     }
-                    self.inode_to_path.insert(child.attr.ino, child);
-                    *ino_counter += 1;
+                    FileType::NamedPipe
+                    | FileType::CharDevice
+                    | FileType::BlockDevice
+                    | FileType::Socket => TypeExtra::Special,
+                };
+                let child = FileInfo {
+                    parent: ino,
+                    path,
+                    attr,
+                    type_extra,
+                    links: Vec::new(),
+                    hash: None,
+                };
+                // avoid this lookup each time with something better?
+                if let Some(TypeExtra::Directory(children)) =
+                    &mut self.inode_to_path.get_mut(&ino).map(|f| &mut f.type_extra)
+                {
+                    children.insert(name, child.attr.ino);
+                } else {
+                    unreachable!("this should be impossible");
                 }
+                self.inode_to_path.insert(child.attr.ino, child);
+                *ino_counter += 1;
             }
         }
     }
 
-    pub fn lookup(&self, parent: u64, child: &OsStr) -> Option<&FileAttr> {
-        let (_, children) = self.folder(parent)?;
-        let child = children.get(child)?;
-        let child = self.inode_to_path.get(child)?;
-        Some(&child.attr)
+    pub fn lookup(&self, parent: u64, child: &OsStr) -> Option<FileAttr> {
+        self.attr(self.child(parent, child)?)
     }
 
-    pub fn getattr(&self, ino: u64) -> Option<&FileAttr> {
-        Some(&self.inode_to_path.get(&ino)?.attr)
+    pub fn getattr(&self, ino: u64) -> Option<FileAttr> {
+        self.attr(ino)
     }
 
-    pub fn folder(&self, ino: u64) -> Option<(&FileInfo, &HashMap<OsString, u64>)> {
-        self.inode_to_path.get(&ino).and_then(|f| {
-            if let TypeExtra::Directory(children) = &f.type_extra {
-                Some((f, children))
-            } else {
-                None
-            }
-        })
+    /// find the inode of a path relative to the root
+    pub fn resolve(&self, path: &Path) -> Option<u64> {
+        path.iter().try_fold(1, |ino, name| self.child(ino, name))
     }
 
-    pub fn symlink(&self, ino: u64) -> Option<(&FileInfo, &OsString)> {
-        self.inode_to_path.get(&ino).and_then(|f| {
-            if let TypeExtra::Symlink(link) = &f.type_extra {
-                Some((f, link))
-            } else {
-                None
+    pub fn folder(&self, ino: u64) -> Option<Cow<'_, HashMap<OsString, u64>>> {
+        match self.get(ino)? {
+            Cow::Borrowed(FileInfo {
+                type_extra: TypeExtra::Directory(children),
+                ..
+            }) => Some(Cow::Borrowed(children)),
+            Cow::Owned(FileInfo {
+                type_extra: TypeExtra::Directory(children),
+                ..
+            }) => Some(Cow::Owned(children)),
+            _ => None,
+        }
+    }
+
+    pub fn symlink(&self, ino: u64) -> Option<Cow<'_, OsStr>> {
+        match self.get(ino)? {
+            Cow::Borrowed(FileInfo {
+                type_extra: TypeExtra::Symlink(link),
+                ..
+            }) => Some(Cow::Borrowed(link.as_os_str())),
+            Cow::Owned(FileInfo {
+                type_extra: TypeExtra::Symlink(link),
+                ..
+            }) => Some(Cow::Owned(link)),
+            _ => None,
+        }
+    }
+
+    pub fn file(&self, ino: u64) -> Option<Cow<'_, FileInfo>> {
+        self.get(ino)
+    }
+
+    /// every regular file at or under ino
+    pub fn files_under(
+        &self,
+        ino: u64,
+    ) -> Vec<(u64, PathBuf, FileAttr, Option<chunk::ContentHash>)> {
+        let mut files = Vec::new();
+        let mut inos = vec![ino];
+        while let Some(ino) = inos.pop() {
+            if let Some(file) = self.file(ino) {
+                match &file.type_extra {
+                    TypeExtra::RegularFile => {
+                        files.push((ino, file.path.clone(), file.attr, file.hash))
+                    }
+                    TypeExtra::Directory(children) => inos.extend(children.values()),
+                    TypeExtra::Symlink(_) | TypeExtra::Special => (),
+                }
             }
-        })
+        }
+        files
     }
+}
+
+#[derive(Debug)]
+enum CachedFile {
+    Full(File),
+    Compressed(compress::Reader),
+    Partial(Arc<Mutex<chunk::PartialFile>>),
+}
 
-    pub fn file(&self, ino: u64) -> Option<&FileInfo> {
-        self.inode_to_path.get(&ino)
+impl CachedFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        use std::os::unix::fs::FileExt;
+        match self {
+            CachedFile::Full(file) => file.read_at(buf, offset),
+            CachedFile::Compressed(file) => file.read_at(buf, offset),
+            CachedFile::Partial(file) => file
+                .lock()
+                .expect("partial file lock poisoned")
+                .read_at(buf, offset),
+        }
     }
 }
 
 #[derive(Debug)]
 struct FileHandle {
-    file: File,
+    // reads clone this so they don't hold the opened files lock while they wait on the remote
+    file: Arc<CachedFile>,
+    path: PathBuf,
     count: usize,
 }
 
 impl FileHandle {
-    fn new(file: File) -> Self {
-        FileHandle { file, count: 1 }
+    fn new(file: CachedFile, path: PathBuf) -> Self {
+        FileHandle {
+            file: Arc::new(file),
+            path,
+            count: 1,
+        }
     }
 
     fn open(&mut self) {
@@ -292,34 +736,306 @@ impl FileHandle {
     }
 }
 
-impl Deref for FileHandle {
-    type Target = File;
+thread_local! {
+    // one per worker, reads are usually the same size every time
+    static READ_BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(4096));
+}
+
+/// read size bytes at offset, short only at the end of the file
+fn read_full(file: &CachedFile, offset: u64, size: usize, reply: ReplyData) {
+    READ_BUFFER.with(|b| {
+        let b = &mut *b.borrow_mut();
+        if b.len() != size {
+            b.resize(size, 0);
+        }
+
+        let mut bo = 0;
+        while bo < size {
+            match file.read_at(&mut b[bo..], offset + bo as u64) {
+                Err(e) => return reply.error(errhandle(e)),
+                Ok(0) => {
+                    b.resize(bo, 0);
+                    break;
+                }
+                Ok(ret) => {
+                    bo += ret;
+                }
+            };
+        }
+
+        reply.data(&b[..]);
+    })
+}
+
+/// everything open and read need, cheap to clone into the worker jobs they run as
+#[derive(Clone)]
+struct Files {
+    cache_dir: PathBuf,
+    downloads: Arc<chunk::Downloads>,
+    download: Option<Sender<Arc<Mutex<chunk::PartialFile>>>>,
+    partial_min_size: u64,
+    readahead: u64,
+    // check complete files against the remote's attr before serving them
+    verify: bool,
+    opened: Arc<Mutex<HashMap<u64, FileHandle>>>,
+}
+
+impl Files {
+    fn opened(&self) -> MutexGuard<'_, HashMap<u64, FileHandle>> {
+        self.opened.lock().expect("opened files lock poisoned")
+    }
+
+    /// open ino from the cache, starting to fetch it first if it isn't complete
+    fn open(
+        &self,
+        ino: u64,
+        path: PathBuf,
+        attr: &FileAttr,
+        hash: Option<chunk::ContentHash>,
+    ) -> Result<()> {
+        let mut oo = std::fs::OpenOptions::new();
+        oo.read(true);
+        oo.write(false);
+        oo.create(false);
+        oo.append(false);
+        oo.truncate(false);
+
+        let complete = self.downloads.is_complete(&path, hash.as_ref())
+            && !(self.verify && self.downloads.discard_corrupt(&path, attr, hash.as_ref()));
+        let file = if complete {
+            self.downloads.evictor().touch(&path);
+            let file = oo.open(self.cache_dir.join(&path))?;
+            match compress::seek_table(&file)? {
+                Some(table) => CachedFile::Compressed(compress::Reader::new(file, table)),
+                None => CachedFile::Full(file),
+            }
+        } else {
+            // joins the prefetcher or a previous open if either is already fetching it
+            let file = self
+                .downloads
+                .open(ino, &path, attr, hash, self.readahead)?;
+            // big files only fetch what's actually read, small ones are fetched whole without
+            // making open wait for it
+            if attr.size < self.partial_min_size {
+                if let Some(download) = &self.download {
+                    download.send(file.clone()).ok();
+                }
+            }
+            CachedFile::Partial(file)
+        };
+
+        let mut opened = self.opened();
+        match opened.get_mut(&ino) {
+            // another open of the same file got here first
+            Some(file_handle) => file_handle.open(),
+            None => {
+                self.downloads.evictor().opened(&path);
+                opened.insert(ino, FileHandle::new(file, path));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// mount options that change how files are cached
+struct Config {
+    rescan_interval: Option<Duration>,
+    max_cache_size: Option<u64>,
+    // files at least this big are cached chunk by chunk as they are read, smaller ones are
+    // downloaded whole in the background
+    partial_min_size: u64,
+    readahead: u64,
+    // workers for opens and reads, which can block on the remote
+    threads: usize,
+    // how long the remote gets to answer a probe before it's considered offline
+    remote_timeout: Duration,
+    // check cached files before opening them, see Files
+    verify: bool,
+    // cache files by their contents, see store::Store
+    dedup: bool,
+    // zstd level complete files are compressed with
+    compress: Option<i32>,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.file
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            rescan_interval: None,
+            max_cache_size: None,
+            partial_min_size: DEFAULT_PARTIAL_MIN_SIZE,
+            readahead: DEFAULT_READAHEAD,
+            threads: pool::DEFAULT_THREADS,
+            remote_timeout: health::DEFAULT_TIMEOUT,
+            verify: false,
+            dedup: false,
+            compress: None,
+        }
     }
 }
 
 struct CacheFs {
     remote_dir: PathBuf,
     cache_dir: PathBuf,
-    cache_tmp_file: PathBuf,
-    tree: FileTree,
-    opened_files: HashMap<u64, FileHandle>,
-    read_buffer: Vec<u8>,
+    chunks_dir: PathBuf,
+    index_path: PathBuf,
+    pins_path: PathBuf,
+    tree: Arc<RwLock<FileTree>>,
+    files: Files,
+    prefetch: Option<Sender<PathBuf>>,
+    pool: Option<pool::Pool>,
+    config: Config,
 }
 
 impl CacheFs {
-    pub fn new(remote_dir: PathBuf, cache_dir: PathBuf, tree: FileTree) -> CacheFs {
+    pub fn new(
+        remote_dir: PathBuf,
+        remote: Arc<dyn RemoteBackend>,
+        cache_dir: PathBuf,
+        tree: Arc<RwLock<FileTree>>,
+        health: Arc<health::Health>,
+        config: Config,
+    ) -> CacheFs {
+        chunk::cleanup(&cache_dir);
+        let store = store::Store::open(&cache_dir, config.dedup)
+            .unwrap_or_else(|e| panic!("cannot create {:?}: {e}", store::OBJECTS_DIR));
+        let evictor = evict::Evictor::load(&cache_dir, config.max_cache_size);
+        let mut downloads = chunk::Downloads::new(
+            remote,
+            cache_dir.join("root"),
+            cache_dir.join(chunk::CHUNKS_DIR),
+            cache_dir.join(chunk::TMP_DIR),
+            Arc::new(Mutex::new(evictor)),
+            health,
+            store,
+        );
+        if let Some(level) = config.compress {
+            downloads.compress(level);
+        }
         CacheFs {
             remote_dir,
             cache_dir: cache_dir.join("root"),
-            cache_tmp_file: cache_dir.join("tmp.file"),
+            chunks_dir: cache_dir.join(chunk::CHUNKS_DIR),
+            index_path: cache_dir.join(index::INDEX_NAME),
+            pins_path: cache_dir.join(pin::PINS_NAME),
             tree,
-            opened_files: HashMap::with_capacity(2),
-            read_buffer: Vec::with_capacity(4096),
+            files: Files {
+                cache_dir: cache_dir.join("root"),
+                downloads: Arc::new(downloads),
+                download: None,
+                partial_min_size: config.partial_min_size,
+                readahead: config.readahead,
+                verify: config.verify,
+                opened: Arc::new(Mutex::new(HashMap::with_capacity(2))),
+            },
+            prefetch: None,
+            pool: None,
+            config,
+        }
+    }
+
+    fn tree(&self) -> RwLockReadGuard<'_, FileTree> {
+        self.tree.read().expect("tree lock poisoned")
+    }
+
+    fn evictor(&self) -> MutexGuard<'_, evict::Evictor> {
+        self.files.downloads.evictor()
+    }
+
+    fn xattr(
+        &self,
+        path: &Path,
+        kind: FileType,
+        name: &OsStr,
+    ) -> std::result::Result<Vec<u8>, c_int> {
+        let name = match xattr_names(kind).iter().find(|n| name == **n) {
+            Some(name) => *name,
+            None => return Err(ENODATA),
+        };
+        let value = match name {
+            XATTR_PINNED => {
+                let pins = pin::Pins::load(&self.pins_path).map_err(errhandle)?;
+                if pins.is_pinned(path) { "1" } else { "0" }.to_string()
+            }
+            XATTR_REMOTE_PATH => {
+                return Ok(self.remote_dir.join(path).as_os_str().as_bytes().to_vec())
+            }
+            XATTR_LAST_ACCESS => match self.evictor().last_access(path) {
+                // seconds since the epoch, like stat -c %X
+                Some(time) => time
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+                    .to_string(),
+                None => return Err(ENODATA),
+            },
+            _ => {
+                let cached = chunk::cached(&self.cache_dir, &self.chunks_dir, path);
+                match (name, cached) {
+                    (XATTR_CACHED, chunk::Cached::None) => "none".to_string(),
+                    (XATTR_CACHED, chunk::Cached::Partial(_)) => "partial".to_string(),
+                    (XATTR_CACHED, chunk::Cached::Full(_)) => "full".to_string(),
+                    (_, chunk::Cached::None) => "0".to_string(),
+                    (_, chunk::Cached::Partial(bytes) | chunk::Cached::Full(bytes)) => {
+                        bytes.to_string()
+                    }
+                }
+            }
+        };
+        Ok(value.into_bytes())
+    }
+
+    /// run blocking work on the pool, or right here if it couldn't be started
+    fn run(&self, job: impl FnOnce() + Send + 'static) {
+        match &self.pool {
+            Some(pool) => pool.run(job),
+            None => job(),
         }
     }
+
+    fn set_pinned(&self, ino: u64, pinned: bool) -> std::result::Result<(), c_int> {
+        let path = match self.tree().file(ino) {
+            None => return Err(ENOENT),
+            Some(file) => file.path.clone(),
+        };
+        let mut pins = pin::Pins::load(&self.pins_path).map_err(errhandle)?;
+        let changed = if pinned {
+            pins.pin(&path)
+        } else {
+            pins.unpin(&path)
+        };
+        if changed {
+            pins.save(&self.pins_path).map_err(errhandle)?;
+            if let (true, Some(prefetch)) = (pinned, &self.prefetch) {
+                prefetch.send(path).ok();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the xattrs a file of kind has, pinned first as it's the only one that can be set
+fn xattr_names(kind: FileType) -> &'static [&'static str] {
+    match kind {
+        FileType::RegularFile => &[
+            XATTR_PINNED,
+            XATTR_CACHED,
+            XATTR_CACHED_BYTES,
+            XATTR_REMOTE_PATH,
+            XATTR_LAST_ACCESS,
+        ],
+        _ => &[XATTR_PINNED, XATTR_REMOTE_PATH],
+    }
+}
+
+/// reply to getxattr/listxattr, value is the size if size is 0 like the kernel expects
+fn reply_xattr(size: u32, value: &[u8], reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
 }
 
 fn ft2ft(t: std::fs::FileType) -> Result<FileType> {
@@ -327,6 +1043,10 @@ fn ft2ft(t: std::fs::FileType) -> Result<FileType> {
         x if x.is_symlink() => Ok(FileType::Symlink),
         x if x.is_dir() => Ok(FileType::Directory),
         x if x.is_file() => Ok(FileType::RegularFile),
+        x if x.is_fifo() => Ok(FileType::NamedPipe),
+        x if x.is_char_device() => Ok(FileType::CharDevice),
+        x if x.is_block_device() => Ok(FileType::BlockDevice),
+        x if x.is_socket() => Ok(FileType::Socket),
         _ => Err(Error::from(ErrorKind::NotFound)),
     }
 }
@@ -352,6 +1072,10 @@ fn meta2attr(m: &std::fs::Metadata, ino: u64) -> Result<FileAttr> {
 }
 
 fn errhandle(e: Error) -> libc::c_int {
+    // the remote is offline, see health
+    if e.raw_os_error() == Some(EHOSTDOWN) {
+        return EHOSTDOWN;
+    }
     match e.kind() {
         ErrorKind::PermissionDenied => EPERM,
         ErrorKind::NotFound => ENOENT,
@@ -362,38 +1086,141 @@ fn errhandle(e: Error) -> libc::c_int {
     }
 }
 
+/// delete cached copies (and chunk maps) of anything removed or changed on the remote
+fn invalidate_cached(cache_dir: &Path, chunks_dir: &Path, changes: &[TreeChange]) {
+    for change in changes {
+        let path = match change {
+            TreeChange::Removed { path, .. } | TreeChange::Changed { path, .. } => path,
+            TreeChange::Added { path, .. } => {
+                debug!("new on remote {:?}", path);
+                continue;
+            }
+        };
+        for path in [cache_dir.join(path), chunk::map_path(chunks_dir, path)] {
+            let ret = if path.is_dir() {
+                std::fs::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(&path)
+            };
+            match ret {
+                Ok(_) => debug!("invalidated cached {:?}", path),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => error!("cannot invalidate cached {:?}: {:?}", path, e),
+            }
+        }
+    }
+}
+
 impl Filesystem for CacheFs {
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        _config: &mut KernelConfig,
+    ) -> std::result::Result<(), c_int> {
+        // in case max_cache_size was lowered since last time
+        self.evictor().make_room(0);
+
+        if let Err(e) = self.files.downloads.health.clone().spawn() {
+            error!("cannot start health thread: {:?}", e);
+        }
+
+        if let Err(e) = evict::Evictor::spawn_saver(self.files.downloads.evictor.clone()) {
+            error!("cannot start saving cache access times: {:?}", e);
+        }
+
+        match pool::Pool::spawn(self.config.threads) {
+            Ok(pool) => self.pool = Some(pool),
+            Err(e) => error!("cannot start worker threads: {:?}", e),
+        }
+
+        match chunk::Downloader::spawn(self.files.downloads.evictor.clone()) {
+            Ok(download) => self.files.download = Some(download),
+            Err(e) => error!("cannot start download threads: {:?}", e),
+        }
+
+        let prefetcher = pin::Prefetcher {
+            tree: self.tree.clone(),
+            downloads: self.files.downloads.clone(),
+        };
+        match prefetcher.spawn() {
+            Ok(prefetch) => self.prefetch = Some(prefetch),
+            Err(e) => error!("cannot start prefetch thread: {:?}", e),
+        }
+        if let Some(prefetch) = &self.prefetch {
+            pin::prefetch_all(&self.pins_path, prefetch);
+        }
+
+        // this runs after we've forked and mounted, so threads and the fuse fd are ours now
+        if let Some(interval) = self.config.rescan_interval {
+            let notifier = match notify::Notifier::find() {
+                Ok(notifier) => Some(notifier),
+                Err(e) => {
+                    warn!("cannot notify kernel of changes, listings may be stale until TTL expires: {:?}", e);
+                    None
+                }
+            };
+            let rescanner = rescan::Rescanner {
+                remote_dir: self.remote_dir.clone(),
+                remote: self.files.downloads.remote.clone(),
+                cache_dir: self.cache_dir.clone(),
+                chunks_dir: self.chunks_dir.clone(),
+                index_path: self.index_path.clone(),
+                tree: self.tree.clone(),
+                evictor: self.files.downloads.evictor.clone(),
+                pins_path: self.pins_path.clone(),
+                prefetch: self.prefetch.clone(),
+                health: self.files.downloads.health.clone(),
+                interval,
+            };
+            if let Err(e) = rescanner.spawn(notifier) {
+                error!("cannot start rescan thread: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        if let Err(e) = self.evictor().save() {
+            error!("cannot save cache access times: {:?}", e);
+        }
+    }
+
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         debug!("lookup: parent: {parent}, name: {:?}", name);
-        match self.tree.lookup(parent, name) {
+        match self.tree().lookup(parent, name) {
             None => reply.error(ENOENT),
-            Some(attr) => reply.entry(&TTL, attr, 1),
+            Some(attr) => reply.entry(&TTL, &attr, 1),
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         debug!("getattr: ino: {ino}");
-        match self.tree.getattr(ino) {
+        match self.tree().getattr(ino) {
             None => reply.error(ENOENT),
-            Some(attr) => reply.attr(&TTL, attr),
+            Some(attr) => reply.attr(&TTL, &attr),
         }
     }
 
     fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         debug!("open: ino: {ino}, flags: {flags}");
 
-        if let Some(file_handle) = self.opened_files.get_mut(&ino) {
+        if let Some(file_handle) = self.files.opened().get_mut(&ino) {
             file_handle.open();
             return reply.opened(ino, 0);
         }
 
-        let entry_path = match self.tree.file(ino) {
+        let (entry_path, attr, hash) = match self.tree().file(ino) {
             None => return reply.error(ENOENT),
-            Some(file) => &file.path,
+            Some(file) => (file.path.clone(), file.attr, file.hash),
         };
 
         debug!("open: entry_path: {:?}", entry_path);
 
+        // the kernel opens fifos and device nodes itself, there's nothing of them to cache
+        if attr.kind != FileType::RegularFile {
+            return reply.error(ENXIO);
+        }
+
         let fl = flags as c_int;
 
         if !matches!(fl & O_ACCMODE, O_RDONLY | O_WRONLY | O_RDWR) {
@@ -405,55 +1232,17 @@ impl Filesystem for CacheFs {
             return reply.error(EIO);
         }
 
-        let mut oo = std::fs::OpenOptions::new();
-        oo.read(true);
-        oo.write(false);
-        oo.create(false);
-        oo.append(false);
-        oo.truncate(false);
-
-        let cache_path = self.cache_dir.join(&entry_path);
-        if !&cache_path.exists() {
-            // copy the file into place
-            // todo: handle these errors
-            if let Some(parent) = cache_path.parent() {
-                if let Err(e) = std::fs::create_dir_all(parent) {
-                    error!("cannot create cache dir {:?} to copy into: {:?}", parent, e);
-                    return reply.error(EIO);
-                }
-            }
-            let remote_path = self.remote_dir.join(entry_path);
-            debug!(
-                "copying from {:?} to {:?}",
-                remote_path, self.cache_tmp_file
-            );
-            if let Err(e) = std::fs::copy(&remote_path, &self.cache_tmp_file) {
-                error!(
-                    "failed to copy from {:?} to {:?}: {:?}",
-                    &remote_path, self.cache_tmp_file, e
-                );
-                return reply.error(EIO);
-            }
-            debug!("moving from {:?} to {:?}", self.cache_tmp_file, cache_path);
-            if let Err(e) = std::fs::rename(&self.cache_tmp_file, &cache_path) {
-                error!(
-                    "failed to move from {:?} to {:?}: {:?}",
-                    self.cache_tmp_file, cache_path, e
-                );
-                // try to delete it in case it partially moved or something (shouldn't happen, should always be atomic)
-                // but ignore any error deleting it because what could we do anyway?
-                std::fs::remove_file(cache_path).ok();
-                return reply.error(EIO);
-            }
+        // mounting rw only exists so xattrs can be set, file contents are never writable
+        if fl & O_ACCMODE != O_RDONLY {
+            return reply.error(EROFS);
         }
 
-        match oo.open(cache_path) {
+        // creating the cache file and starting its download can block on the remote
+        let files = self.files.clone();
+        self.run(move || match files.open(ino, entry_path, &attr, hash) {
             Err(e) => reply.error(errhandle(e)),
-            Ok(f) => {
-                self.opened_files.insert(ino, FileHandle::new(f));
-                reply.opened(ino, 0);
-            }
-        }
+            Ok(_) => reply.opened(ino, 0),
+        });
     }
 
     fn read(
@@ -468,35 +1257,13 @@ impl Filesystem for CacheFs {
         reply: ReplyData,
     ) {
         debug!("read: ino: {ino}, fh: {fh}, offset: {offset}, size: {size}");
-        let f = match self.opened_files.get(&fh) {
+        let file = match self.files.opened().get(&fh) {
             None => return reply.error(EIO),
-            Some(x) => x,
+            Some(x) => x.file.clone(),
         };
 
-        let size = size as usize;
-
-        let b = &mut self.read_buffer;
-        if b.len() != size {
-            b.resize(size, 0);
-        }
-
-        use std::os::unix::fs::FileExt;
-
-        let mut bo = 0;
-        while bo < size {
-            match f.read_at(&mut b[bo..], offset as u64) {
-                Err(e) => return reply.error(errhandle(e)),
-                Ok(0) => {
-                    b.resize(bo, 0);
-                    break;
-                }
-                Ok(ret) => {
-                    bo += ret;
-                }
-            };
-        }
-
-        reply.data(&b[..]);
+        // missing chunks are fetched from the remote as part of the read
+        self.run(move || read_full(&file, offset as u64, size as usize, reply));
     }
 
     fn release(
@@ -514,13 +1281,16 @@ impl Filesystem for CacheFs {
         // 1. optimize for many simultaneously opened files in which case we'd get_mut, and then remove if required
         // 2. optimize for normally only 1 simultaneously opened file, so removing and then only adding back if keeping is best
         // we pick #2
-        let mut file_handle = match self.opened_files.remove(&fh) {
+        let mut opened = self.files.opened();
+        let mut file_handle = match opened.remove(&fh) {
             None => return reply.error(EIO),
             Some(x) => x,
         };
 
-        if !file_handle.close() {
-            self.opened_files.insert(fh, file_handle);
+        if file_handle.close() {
+            self.evictor().closed(&file_handle.path);
+        } else {
+            opened.insert(fh, file_handle);
         }
 
         reply.ok();
@@ -528,7 +1298,7 @@ impl Filesystem for CacheFs {
 
     fn opendir(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         debug!("opendir: ino: {ino}, flags: {flags}");
-        match self.tree.getattr(ino) {
+        match self.tree().getattr(ino) {
             None => reply.error(ENOENT),
             Some(attr) => reply.opened(attr.ino, 0),
         }
@@ -544,43 +1314,36 @@ impl Filesystem for CacheFs {
     ) {
         debug!("readdir: ino: {ino}, fh: {fh}, offset: {offset}");
 
-        let (dir, children) = match self.tree.folder(ino) {
-            None => return reply.error(EIO),
-            Some(x) => x,
+        let tree = self.tree();
+        let skip = if offset <= 1 { 0 } else { offset as usize - 2 };
+        let (parent, children) = match (tree.parent(ino), tree.children(ino, skip)) {
+            (Some(parent), Some(children)) => (parent, children),
+            _ => return reply.error(EIO),
         };
 
-        if offset == 0
-            && reply.add(
-                dir.attr.ino,
-                1,
-                FileType::Directory,
-                OsStr::from_bytes(b"."),
-            )
-        {
+        if offset == 0 && reply.add(ino, 1, FileType::Directory, OsStr::from_bytes(b".")) {
             return reply.ok();
         }
 
-        if offset <= 1 && reply.add(dir.parent, 2, FileType::Directory, OsStr::from_bytes(b"..")) {
+        if offset <= 1 && reply.add(parent, 2, FileType::Directory, OsStr::from_bytes(b"..")) {
             return reply.ok();
         }
 
-        let offset = if offset <= 1 { 0 } else { offset as usize - 2 };
-
-        for (i, (name, ino)) in children.iter().enumerate().skip(offset) {
-            let file = match self.tree.file(*ino) {
-                Some(file) => file,
+        for (i, (name, ino)) in children.enumerate() {
+            let attr = match tree.getattr(ino) {
+                Some(attr) => attr,
                 None => {
                     error!("should be impossible to not be able to find a child");
                     return reply.error(EIO);
                 }
             };
-            // i + 3 means the index of the next entry
-            let offset = (i + 3) as i64;
+            // skip + i + 3 means the index of the next entry
+            let offset = (skip + i + 3) as i64;
             debug!(
                 "sending ino: {}, offset: {}, kind: {:?}, name: {:?}",
-                *ino, offset, file.attr.kind, name
+                ino, offset, attr.kind, name
             );
-            if reply.add(*ino, offset, file.attr.kind, name) {
+            if reply.add(ino, offset, attr.kind, name) {
                 break;
             }
         }
@@ -590,7 +1353,7 @@ impl Filesystem for CacheFs {
     fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
         debug!("releasedir: ino: {ino}, fh: {fh}, flags: {flags}");
         // or could just always return ok() ?
-        match self.tree.file(ino) {
+        match self.tree().attr(ino) {
             None => reply.error(EIO),
             Some(_) => reply.ok(),
         };
@@ -598,12 +1361,82 @@ impl Filesystem for CacheFs {
 
     fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
         debug!("readlink: ino: {ino}");
-        let (_, link) = match self.tree.symlink(ino) {
+        let tree = self.tree();
+        let link = match tree.symlink(ino) {
             None => return reply.error(ENOENT),
             Some(x) => x,
         };
         reply.data(link.as_bytes());
     }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("setxattr: ino: {ino}, name: {:?}, value: {:?}", name, value);
+        if name != XATTR_PINNED {
+            return reply.error(ENOTSUP);
+        }
+        let pinned = match value {
+            b"1" | b"true" | b"yes" => true,
+            b"0" | b"false" | b"no" | b"" => false,
+            _ => return reply.error(EINVAL),
+        };
+        match self.set_pinned(ino, pinned) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        debug!("getxattr: ino: {ino}, name: {:?}, size: {size}", name);
+        let (path, kind) = match self.tree().file(ino) {
+            None => return reply.error(ENOENT),
+            Some(file) => (file.path.clone(), file.attr.kind),
+        };
+        match self.xattr(&path, kind, name) {
+            Ok(value) => reply_xattr(size, &value, reply),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr: ino: {ino}, size: {size}");
+        let kind = match self.tree().getattr(ino) {
+            None => return reply.error(ENOENT),
+            Some(attr) => attr.kind,
+        };
+        let mut names = Vec::new();
+        for name in xattr_names(kind) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        reply_xattr(size, &names, reply);
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr: ino: {ino}, name: {:?}", name);
+        if name != XATTR_PINNED {
+            return reply.error(ENODATA);
+        }
+        match self.set_pinned(ino, false) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
 }
 
 pub fn daemon() {
@@ -630,36 +1463,100 @@ fn main() {
     env_logger::init();
     let mut args = env::args_os().skip(1);
     let mut cmd_opts = "ro".to_string();
-    let mut remote_dir = "".to_string();
+    // in order of priority, see failover
+    let mut remote_dirs = Vec::new();
     let mut default_permissions = true;
     let mut fork_daemon = true;
+    let mut read_write = false;
+    let mut refresh = false;
+    let mut config = Config::default();
 
     let mut count = 0;
     let mut pos_args = [None, None];
 
     while let Some(arg) = args.next() {
-        if arg == "-c" {
+        if count == 0 && (arg == "pin" || arg == "unpin" || arg == "pins") {
+            pin::command(&arg, args);
+            return;
+        } else if count == 0 && arg == "warm" {
+            warm::command(args);
+            return;
+        } else if count == 0 && arg == "index" {
+            inspect::command(args);
+            return;
+        } else if count == 0 && arg == "verify" {
+            verify::command(args);
+            return;
+        } else if arg == "-c" {
             let root_path = PathBuf::from(args.next().expect("found -o but missing opts"));
-            let tree = FileTree::build(&root_path);
-            let path = root_path.join(INDEX_NAME.to_owned() + ".tmp");
-            tree.save(&path).expect("failed to save index");
-            std::fs::rename(path, root_path.join(INDEX_NAME)).expect("failed to rename index");
+            let index_path = root_path.join(index::INDEX_NAME);
+            let remote = remote::Local::new(root_path.clone());
+            let mut tree = FileTree::build(&remote);
+            // any unchanged file keeps the hash it had last time rather than being read again
+            let previous = FileTree::load(&index_path, None).ok();
+            let (hashed, reused, failed) = tree.hash_files(&remote, previous.as_ref());
+            info!("hashed {hashed} files, {reused} unchanged, {failed} unreadable");
+            tree.save(&index_path, &root_path)
+                .expect("failed to save index");
             return;
         } else if arg == "-o" {
             let opts = args.next().expect("found -o but missing opts");
             let opts = opts.to_str().expect("non-utf8 opts").split(',');
             for opt in opts {
-                if opt.starts_with("remote_dir=") {
-                    let mut split_opt = opt.splitn(2, '=');
-                    if let Some(dir) = split_opt.nth(1) {
-                        remote_dir.clear();
-                        remote_dir.push_str(dir);
-                    }
+                if let Some(dir) = opt.strip_prefix("remote_dir=") {
+                    remote_dirs.push(PathBuf::from(dir));
+                    continue;
+                }
+                if let Some(secs) = opt.strip_prefix("rescan=") {
+                    let secs = secs.parse().expect("rescan= must be a number of seconds");
+                    config.rescan_interval = Some(Duration::from_secs(secs));
+                    continue;
+                }
+                if let Some(size) = opt.strip_prefix("max_cache_size=") {
+                    let size = evict::parse_size(size)
+                        .expect("max_cache_size= must be a size like 1024, 512M or 20G");
+                    config.max_cache_size = Some(size);
+                    continue;
+                }
+                if let Some(size) = opt.strip_prefix("partial_min_size=") {
+                    config.partial_min_size = evict::parse_size(size)
+                        .expect("partial_min_size= must be a size like 1024, 512M or 20G");
+                    continue;
+                }
+                if let Some(size) = opt.strip_prefix("readahead=") {
+                    config.readahead = evict::parse_size(size)
+                        .expect("readahead= must be a size like 1024, 512M or 20G");
+                    continue;
+                }
+                if let Some(level) = opt.strip_prefix("compress=") {
+                    let level = level
+                        .parse()
+                        .expect("compress= must be a zstd level like 3");
+                    config.compress = Some(level);
+                    continue;
+                }
+                if let Some(secs) = opt.strip_prefix("remote_timeout=") {
+                    let secs = secs
+                        .parse()
+                        .expect("remote_timeout= must be a number of seconds");
+                    config.remote_timeout = Duration::from_secs(secs);
+                    continue;
+                }
+                if let Some(threads) = opt.strip_prefix("threads=") {
+                    config.threads = threads
+                        .parse()
+                        .expect("threads= must be a number of worker threads");
                     continue;
                 }
                 match opt {
-                    "ro" | "rw" => (),
+                    "ro" => read_write = false,
+                    // only needed to set xattrs, see open
+                    "rw" => read_write = true,
                     "no_default_permissions" => default_permissions = false,
+                    "refresh" => refresh = true,
+                    "verify" => config.verify = true,
+                    "dedup" => config.dedup = true,
+                    "compress" => config.compress = Some(compress::DEFAULT_LEVEL),
                     "no_daemon" | "no_fork" | "nodaemon" | "nofork" => fork_daemon = false,
                     opt => {
                         cmd_opts.push(',');
@@ -667,9 +1564,12 @@ fn main() {
                     }
                 }
             }
-            if remote_dir.is_empty() {
+            if remote_dirs.is_empty() {
                 panic!("must supply remote_dir=/path/to/remote to -o")
             }
+            if read_write {
+                cmd_opts.replace_range(..2, "rw");
+            }
             if !cmd_opts.contains(",fsname=") {
                 cmd_opts.push_str(",fsname=cachefs");
             }
@@ -686,18 +1586,78 @@ fn main() {
 
     let cache_dir = PathBuf::from(pos_args[0].as_ref().expect("missing cache_dir"));
     let mountpoint = pos_args[1].as_ref().expect("missing mountpoint");
-    let remote_dir = PathBuf::from(remote_dir);
+    // the tree and index come from the first
+    let remote_dir = remote_dirs[0].clone();
+    let remotes: Vec<Arc<dyn RemoteBackend>> =
+        remote_dirs.iter().map(|dir| remote::open(dir)).collect();
+    let remote = remotes[0].clone();
 
     debug!(
         "mounting {:?} on {:?} with cache_dir: {:?}, opts: {cmd_opts}",
-        remote_dir, mountpoint, cache_dir
+        remote_dirs, mountpoint, cache_dir
     );
 
     std::fs::create_dir_all(&cache_dir).expect("could not create cache_dir");
-    let tree = FileTree::load_or_build(remote_dir.deref(), cache_dir.deref())
-        .expect("could not build file tree");
+    // with no index yet the first being down shouldn't keep a failover mount from starting, the
+    // tree comes from the next one that answers until a refresh or rescan reaches the first
+    let builder = if remotes.len() > 1 && !cache_dir.join(index::INDEX_NAME).exists() {
+        let online = remotes
+            .iter()
+            .position(|remote| health::Health::new(remote.clone(), config.remote_timeout).probe())
+            .unwrap_or(0);
+        if online > 0 {
+            warn!(
+                "{:?} is offline, building the tree from {:?}",
+                remote_dir, remote_dirs[online]
+            );
+        }
+        remotes[online].clone()
+    } else {
+        remote.clone()
+    };
+    let mut tree = FileTree::load_or_build(builder.deref(), &remote_dir, cache_dir.deref())
+        .unwrap_or_else(|e| panic!("could not load file tree: {e}"));
+
+    if refresh {
+        // a hung remote would otherwise hang the mount before it even starts
+        let health = health::Health::new(remote.clone(), config.remote_timeout);
+        health.probe();
+        match health.check().and_then(|_| tree.refresh(remote.deref())) {
+            Ok(changes) if changes.is_empty() => debug!("refresh: no changes"),
+            Ok(changes) => {
+                debug!("refresh: {} changes: {:?}", changes.len(), changes);
+                invalidate_cached(
+                    &cache_dir.join("root"),
+                    &cache_dir.join(chunk::CHUNKS_DIR),
+                    &changes,
+                );
+                let index_path = cache_dir.join(index::INDEX_NAME);
+                tree.save(&index_path, &remote_dir)
+                    .expect("failed to save refreshed index");
+                // map the saved one rather than keeping every merged entry in memory
+                tree = FileTree::load(&index_path, Some(&remote_dir))
+                    .unwrap_or_else(|e| panic!("could not load refreshed index: {e}"));
+            }
+            Err(e) => warn!(
+                "refresh: cannot scan {:?}, using cached tree: {:?}",
+                remote_dir, e
+            ),
+        }
+    }
 
-    let cache = CacheFs::new(remote_dir, cache_dir, tree);
+    let tree = Arc::new(RwLock::new(tree));
+    let remote: Arc<dyn RemoteBackend> = if remotes.len() > 1 {
+        Arc::new(failover::Failover::new(
+            remotes,
+            tree.clone(),
+            config.remote_timeout,
+        ))
+    } else {
+        remote
+    };
+    // probed again once mounted, a probe thread stuck now wouldn't survive daemonizing anyway
+    let health = Arc::new(health::Health::new(remote.clone(), config.remote_timeout));
+    let cache = CacheFs::new(remote_dir, remote, cache_dir, tree, health, config);
 
     let cmd_opts = OsString::from(cmd_opts);
     let options = [OsStr::new("-o"), cmd_opts.as_os_str()];
@@ -0,0 +1,82 @@
+use log::debug;
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{Error, ErrorKind, Write},
+    os::unix::{ffi::OsStrExt, io::FromRawFd},
+    path::Path,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+// from fuse_kernel.h, fuser 0.11 doesn't expose any way to send these
+const FUSE_NOTIFY_INVAL_INODE: i32 = 2;
+const FUSE_NOTIFY_INVAL_ENTRY: i32 = 3;
+const OUT_HEADER_LEN: usize = 16;
+
+/// sends unsolicited invalidation notifications to the kernel over the /dev/fuse connection
+pub struct Notifier {
+    dev: File,
+}
+
+impl Notifier {
+    /// find the /dev/fuse fd fuser opened for us, must be called after mounting, ie from init
+    pub fn find() -> Result<Self> {
+        for entry in std::fs::read_dir("/proc/self/fd")?.flatten() {
+            if std::fs::read_link(entry.path()).ok().as_deref() != Some(Path::new("/dev/fuse")) {
+                continue;
+            }
+            let fd: i32 = match entry.file_name().to_str().and_then(|fd| fd.parse().ok()) {
+                Some(fd) => fd,
+                None => continue,
+            };
+            // dup it so we own our copy and fuser can close theirs whenever it likes
+            let fd = unsafe { libc::dup(fd) };
+            if fd == -1 {
+                return Err(Error::last_os_error());
+            }
+            debug!("found /dev/fuse as {:?}, duped to {fd}", entry.path());
+            return Ok(Notifier {
+                dev: unsafe { File::from_raw_fd(fd) },
+            });
+        }
+        Err(Error::new(ErrorKind::NotFound, "no open /dev/fuse fd"))
+    }
+
+    /// drop the kernel's cached attributes and data for ino
+    pub fn inval_inode(&self, ino: u64) -> Result<()> {
+        let mut body = Vec::with_capacity(24);
+        body.extend_from_slice(&ino.to_ne_bytes());
+        body.extend_from_slice(&0i64.to_ne_bytes()); // offset
+        body.extend_from_slice(&0i64.to_ne_bytes()); // length, 0 means all
+        self.send(FUSE_NOTIFY_INVAL_INODE, &body)
+    }
+
+    /// drop the kernel's cached lookup of name in parent, positive or negative
+    pub fn inval_entry(&self, parent: u64, name: &OsStr) -> Result<()> {
+        let name = name.as_bytes();
+        let mut body = Vec::with_capacity(16 + name.len() + 1);
+        body.extend_from_slice(&parent.to_ne_bytes());
+        body.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+        body.extend_from_slice(&0u32.to_ne_bytes()); // padding
+        body.extend_from_slice(name);
+        body.push(0);
+        self.send(FUSE_NOTIFY_INVAL_ENTRY, &body)
+    }
+
+    fn send(&self, code: i32, body: &[u8]) -> Result<()> {
+        let len = OUT_HEADER_LEN + body.len();
+        let mut msg = Vec::with_capacity(len);
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&code.to_ne_bytes());
+        msg.extend_from_slice(&0u64.to_ne_bytes()); // unique 0 means notification
+        msg.extend_from_slice(body);
+        // the kernel requires each message in exactly one write
+        match (&self.dev).write(&msg) {
+            // the kernel doesn't have it cached, so nothing to invalidate
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{
+    chunk::{self, ContentHash},
+    FileTree,
+};
+use fuser::FileAttr;
+use log::{debug, error};
+use std::{
+    collections::BTreeSet,
+    ffi::OsStr,
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc, RwLock},
+};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+pub const PINS_NAME: &str = "cache-fs.pins";
+
+/// paths relative to the remote root that are always kept in the cache, one per line on disk so
+/// it's easy to edit by hand, pinning a directory pins everything under it
+#[derive(Default)]
+pub struct Pins {
+    pins: BTreeSet<PathBuf>,
+}
+
+impl Pins {
+    /// a missing file just means nothing is pinned
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Pins::default()),
+            Err(e) => return Err(e),
+        };
+        let mut pins = Pins::default();
+        // split on bytes rather than lines() so non-utf8 names work
+        for line in BufReader::new(file).split(b'\n') {
+            let line = line?;
+            if !line.is_empty() {
+                pins.pins
+                    .insert(normalize(Path::new(OsStr::from_bytes(&line))));
+            }
+        }
+        Ok(pins)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = BufWriter::new(std::fs::File::create(&tmp)?);
+            for pin in &self.pins {
+                file.write_all(pin.as_os_str().as_bytes())?;
+                file.write_all(b"\n")?;
+            }
+            file.flush()?;
+        }
+        std::fs::rename(tmp, path)
+    }
+
+    /// returns false if it was already pinned
+    pub fn pin(&mut self, path: &Path) -> bool {
+        self.pins.insert(normalize(path))
+    }
+
+    /// returns false if it wasn't pinned
+    pub fn unpin(&mut self, path: &Path) -> bool {
+        self.pins.remove(&normalize(path))
+    }
+
+    /// true if path or any directory above it is pinned
+    pub fn is_pinned(&self, path: &Path) -> bool {
+        path.ancestors().any(|p| self.pins.contains(p))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PathBuf> {
+        self.pins.iter()
+    }
+}
+
+/// pins, like any path given on the command line, are always relative to the remote root, so
+/// ignore any leading / or ./
+pub fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect()
+}
+
+/// queue every pin for prefetching, anything already cached is skipped quickly
+pub fn prefetch_all(pins_path: &Path, prefetch: &Sender<PathBuf>) {
+    match Pins::load(pins_path) {
+        Ok(pins) => {
+            for pin in pins.iter() {
+                prefetch.send(pin.clone()).ok();
+            }
+        }
+        Err(e) => error!("cannot load pins {:?}: {:?}", pins_path, e),
+    }
+}
+
+/// copies pinned files into the cache in the background as paths are sent to it
+pub struct Prefetcher {
+    pub tree: Arc<RwLock<FileTree>>,
+    pub downloads: Arc<chunk::Downloads>,
+}
+
+impl Prefetcher {
+    pub fn spawn(self) -> std::io::Result<Sender<PathBuf>> {
+        let (tx, rx) = std::sync::mpsc::channel::<PathBuf>();
+        std::thread::Builder::new()
+            .name("prefetch".to_string())
+            .spawn(move || {
+                for path in rx {
+                    self.prefetch(&path);
+                }
+            })?;
+        Ok(tx)
+    }
+
+    fn prefetch(&self, path: &Path) {
+        let files = self.files_under(path);
+        debug!("prefetch: {:?} has {} files", path, files.len());
+        for (ino, path, attr, hash) in files {
+            if self.downloads.is_complete(&path, hash.as_ref()) {
+                continue;
+            }
+            if !self.downloads.health.is_online() {
+                // the rescan after it's back online queues every pin again
+                debug!("prefetch: remote offline, giving up on {:?} for now", path);
+                return;
+            }
+            // shares the download with anyone that has it open
+            let file = self
+                .downloads
+                .open(ino, &path, &attr, hash, 0)
+                .and_then(|file| {
+                    self.downloads.evictor().opened(&path);
+                    let ret = chunk::download(&file);
+                    self.downloads.evictor().closed(&path);
+                    ret
+                });
+            if let Err(e) = file {
+                // most likely the remote is gone, it'll be tried again next time
+                error!("prefetch: cannot fetch {:?}: {:?}", path, e);
+                return;
+            }
+        }
+    }
+
+    /// every regular file at or under path
+    fn files_under(&self, path: &Path) -> Vec<(u64, PathBuf, FileAttr, Option<ContentHash>)> {
+        let tree = self.tree.read().expect("tree lock poisoned");
+        match tree.resolve(path) {
+            Some(ino) => tree.files_under(ino),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// `cache-fs pin|unpin|pins /local/cache/dir [paths relative to the remote root...]`
+pub fn command(cmd: &OsStr, mut args: impl Iterator<Item = std::ffi::OsString>) {
+    let cache_dir = PathBuf::from(args.next().expect("missing cache_dir"));
+    let pins_path = cache_dir.join(PINS_NAME);
+    let mut pins = Pins::load(&pins_path).expect("cannot load pins");
+    if cmd == "pins" {
+        for pin in pins.iter() {
+            println!("{}", pin.display());
+        }
+        return;
+    }
+    let mut changed = false;
+    for path in args {
+        let path = Path::new(&path);
+        changed |= if cmd == "pin" {
+            pins.pin(path)
+        } else {
+            pins.unpin(path)
+        };
+    }
+    if changed {
+        pins.save(&pins_path).expect("cannot save pins");
+    }
+}
@@ -0,0 +1,52 @@
+use log::{debug, error};
+use std::{
+    panic::AssertUnwindSafe,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub const DEFAULT_THREADS: usize = 8;
+
+/// a fixed set of threads that run whatever blocking work is sent to them, so the fuse session
+/// thread can go straight back to answering other requests
+pub struct Pool {
+    tx: Sender<Job>,
+}
+
+impl Pool {
+    pub fn spawn(threads: usize) -> std::io::Result<Self> {
+        let (tx, rx) = std::sync::mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            std::thread::Builder::new()
+                .name(format!("worker-{i}"))
+                .spawn(move || Self::work(&rx))?;
+        }
+        debug!("started {threads} workers");
+        Ok(Pool { tx })
+    }
+
+    fn work(rx: &Mutex<Receiver<Job>>) {
+        loop {
+            // only hold the lock while waiting, not while running the job
+            let job = match rx.lock().expect("pool lock poisoned").recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            // a panicking job would otherwise take the worker with it, and once they're all gone
+            // so would the fuse session, its reply is dropped which answers EIO
+            if std::panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                error!("a job on {:?} panicked", std::thread::current().name());
+            }
+        }
+    }
+
+    pub fn run(&self, job: impl FnOnce() + Send + 'static) {
+        self.tx.send(Box::new(job)).expect("all workers died");
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{http::Http, meta2attr, s3::S3, Result};
+use fuser::{FileAttr, FileType};
+use std::{
+    ffi::OsString,
+    fmt::Debug,
+    fs::File,
+    io::{Error, ErrorKind, Read},
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+/// how long the remotes over a network wait to connect
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// and for each read, which is at most readahead, anything slower than this is as good as hung
+pub const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// where the files being cached come from, every path is relative to the remote root, which is ""
+pub trait RemoteBackend: Debug + Send + Sync {
+    /// every entry of dir, leaving out any that can't be stat'd
+    fn read_dir(&self, dir: &Path) -> Result<Vec<(OsString, Stat)>>;
+
+    fn stat(&self, path: &Path) -> Result<Stat>;
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf>;
+
+    /// open a regular file for ranged reads
+    fn open(&self, path: &Path) -> Result<Box<dyn RemoteFile>>;
+
+    /// the cheapest request that shows the remote is answering, see health::Health
+    fn probe(&self) -> Result<()> {
+        self.read_dir(Path::new("")).map(|_| ())
+    }
+}
+
+/// an open remote file
+pub trait RemoteFile: Debug + Send {
+    /// read into buf from offset, returns how much was read, 0 at the end of the file
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
+
+    /// fill buf from offset, an error if the file ends first
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// what the remote says an entry is
+#[derive(Clone, Debug)]
+pub struct Stat {
+    // ino is left 0, the tree numbers entries itself
+    pub attr: FileAttr,
+    // the same for every name of a regular file with hard links, None if it only has the one or
+    // the remote can't tell
+    pub link_id: Option<(u64, u64)>,
+}
+
+/// the backend for remote_dir, a URL or else a local directory
+pub fn open(remote_dir: &Path) -> Arc<dyn RemoteBackend> {
+    match remote_dir.to_str() {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            Arc::new(Http::new(url))
+        }
+        Some(url) if url.starts_with("s3://") || url.starts_with("s3+") => Arc::new(S3::new(url)),
+        #[cfg(feature = "sftp")]
+        Some(url) if url.starts_with("sftp://") => Arc::new(crate::sftp::Sftp::new(url)),
+        #[cfg(not(feature = "sftp"))]
+        Some(url) if url.starts_with("sftp://") => {
+            panic!("{url}: this cache-fs was built without the sftp feature")
+        }
+        _ => Arc::new(Local::new(remote_dir.to_path_buf())),
+    }
+}
+
+/// remote_dir spelled one way whichever way it was given, so an index built from it is still
+/// recognised: local paths are made absolute with symlinks resolved (just tidied if the remote is
+/// unreachable), urls lose their trailing slashes and have their scheme lowercased
+pub fn identity(remote_dir: &Path) -> PathBuf {
+    match remote_dir.to_str().and_then(|url| url.split_once("://")) {
+        Some((scheme, rest)) => format!(
+            "{}://{}",
+            scheme.to_ascii_lowercase(),
+            rest.trim_end_matches('/')
+        )
+        .into(),
+        None => remote_dir
+            .canonicalize()
+            .unwrap_or_else(|_| remote_dir.components().collect()),
+    }
+}
+
+/// a directory on this machine, usually a network filesystem like NFS mounted somewhere
+#[derive(Debug)]
+pub struct Local {
+    root: PathBuf,
+}
+
+impl Local {
+    pub fn new(root: PathBuf) -> Self {
+        Local { root }
+    }
+}
+
+fn stat(meta: &std::fs::Metadata) -> Result<Stat> {
+    let attr = meta2attr(meta, 0)?;
+    let link_id =
+        (attr.kind == FileType::RegularFile && meta.nlink() > 1).then(|| (meta.dev(), meta.ino()));
+    Ok(Stat { attr, link_id })
+}
+
+impl RemoteBackend for Local {
+    fn read_dir(&self, dir: &Path) -> Result<Vec<(OsString, Stat)>> {
+        let read_dir = std::fs::read_dir(self.root.join(dir))?;
+        Ok(read_dir
+            .flatten()
+            .filter_map(|de| Some((de.file_name(), stat(&de.metadata().ok()?).ok()?)))
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat> {
+        stat(&std::fs::symlink_metadata(self.root.join(path))?)
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        std::fs::read_link(self.root.join(path))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RemoteFile>> {
+        Ok(Box::new(LocalFile(File::open(self.root.join(path))?)))
+    }
+
+    fn probe(&self) -> Result<()> {
+        // only the first entry, statting all of them could take a while
+        std::fs::read_dir(&self.root)?.next().transpose()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct LocalFile(File);
+
+impl RemoteFile for LocalFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.0.read_at(buf, offset)
+    }
+}
+
+/// read from reader until buf is full or it ends, for remotes that hand over a response or a
+/// protocol's largest read at a time rather than as much as was asked for, returns how much was read
+pub fn fill(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// reads a remote file from the start, for hashing or copying it whole
+#[derive(Debug)]
+pub struct Reader {
+    file: Box<dyn RemoteFile>,
+    offset: u64,
+}
+
+impl Reader {
+    pub fn new(file: Box<dyn RemoteFile>) -> Self {
+        Reader { file, offset: 0 }
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.file.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{
+    evict::Evictor, health::Health, invalidate_cached, notify::Notifier, pin,
+    remote::RemoteBackend, FileTree, TreeChange,
+};
+use log::{debug, error, warn};
+use std::{
+    path::PathBuf,
+    sync::{mpsc::Sender, Arc, Mutex, RwLock},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+// how many intervals to wait at most between attempts while the remote is unreachable
+const MAX_BACKOFF: u32 = 16;
+
+/// periodically re-scans the remote and merges changes into the live tree
+pub struct Rescanner {
+    pub remote_dir: PathBuf,
+    pub remote: Arc<dyn RemoteBackend>,
+    pub cache_dir: PathBuf,
+    pub chunks_dir: PathBuf,
+    pub index_path: PathBuf,
+    pub tree: Arc<RwLock<FileTree>>,
+    pub evictor: Arc<Mutex<Evictor>>,
+    pub pins_path: PathBuf,
+    pub prefetch: Option<Sender<PathBuf>>,
+    pub health: Arc<Health>,
+    pub interval: Duration,
+}
+
+impl Rescanner {
+    pub fn spawn(self, notifier: Option<Notifier>) -> std::io::Result<JoinHandle<()>> {
+        std::thread::Builder::new()
+            .name("rescan".to_string())
+            .spawn(move || self.run(notifier))
+    }
+
+    fn run(self, notifier: Option<Notifier>) {
+        let mut wait = self.interval;
+        loop {
+            std::thread::sleep(wait);
+
+            // scan without holding the lock, this is the slow part
+            let scanned = match self
+                .health
+                .check()
+                .and_then(|_| FileTree::scan(self.remote.as_ref()))
+            {
+                Ok(scanned) => scanned,
+                Err(e) => {
+                    wait = (wait * 2).min(self.interval * MAX_BACKOFF);
+                    debug!(
+                        "rescan: cannot scan {:?}, next try in {:?}: {:?}",
+                        self.remote_dir, wait, e
+                    );
+                    continue;
+                }
+            };
+            wait = self.interval;
+
+            let changes = self
+                .tree
+                .write()
+                .expect("tree lock poisoned")
+                .merge(scanned);
+            // the remote is reachable, so retry any pins that failed and pick up new files in them
+            if let Some(prefetch) = &self.prefetch {
+                pin::prefetch_all(&self.pins_path, prefetch);
+            }
+            if changes.is_empty() {
+                debug!("rescan: no changes");
+                continue;
+            }
+            debug!("rescan: {} changes: {:?}", changes.len(), changes);
+
+            invalidate_cached(&self.cache_dir, &self.chunks_dir, &changes);
+            {
+                let mut evictor = self.evictor.lock().expect("evictor lock poisoned");
+                for change in &changes {
+                    if let TreeChange::Removed { path, .. } | TreeChange::Changed { path, .. } =
+                        change
+                    {
+                        evictor.forget(path);
+                    }
+                }
+            }
+            if let Some(notifier) = &notifier {
+                notify(notifier, &changes);
+            }
+
+            let saved = self
+                .tree
+                .read()
+                .expect("tree lock poisoned")
+                .save(&self.index_path, &self.remote_dir)
+                .and_then(|_| FileTree::load(&self.index_path, Some(&self.remote_dir)));
+            match saved {
+                // map the saved one rather than keeping every merged entry in memory, nothing else
+                // changes the tree so it's the same one
+                Ok(saved) => *self.tree.write().expect("tree lock poisoned") = saved,
+                Err(e) => error!("rescan: failed to save {:?}: {:?}", self.index_path, e),
+            }
+        }
+    }
+}
+
+fn notify(notifier: &Notifier, changes: &[TreeChange]) {
+    for change in changes {
+        let ret = match change {
+            TreeChange::Added { parent, name, .. } | TreeChange::Removed { parent, name, .. } => {
+                notifier
+                    .inval_entry(*parent, name)
+                    .and_then(|_| notifier.inval_inode(*parent))
+            }
+            TreeChange::Changed { ino, .. } => notifier.inval_inode(*ino),
+        };
+        if let Err(e) = ret {
+            warn!(
+                "rescan: kernel notification failed for {:?}: {:?}",
+                change, e
+            );
+        }
+    }
+}
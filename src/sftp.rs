@@ -0,0 +1,428 @@
+use crate::{
+    remote::{self, RemoteBackend, RemoteFile, Stat, CONNECT_TIMEOUT, READ_TIMEOUT},
+    Result,
+};
+use fuser::{FileAttr, FileType};
+use log::debug;
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session};
+use std::{
+    ffi::OsString,
+    fmt::{Debug, Formatter},
+    io::{Error, ErrorKind, Seek, SeekFrom},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+// tried in this order after the agent, like ssh does
+const KEYS: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// a directory on a server reachable over SSH, the same as sshfs but without the extra mount
+///
+/// authenticates like ssh would without asking anything, with the agent or else a key in ~/.ssh
+/// without a passphrase, and the server has to be in ~/.ssh/known_hosts already
+pub struct Sftp {
+    user: String,
+    host: String,
+    port: u16,
+    // absolute, or relative to the home directory on the server
+    root: PathBuf,
+    // connected on first use, and again after the connection drops
+    connection: Mutex<Option<Arc<ssh2::Sftp>>>,
+}
+
+impl Debug for Sftp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sftp {{ user: {:?}, host: {:?}, port: {}, root: {:?} }}",
+            self.user, self.host, self.port, self.root
+        )
+    }
+}
+
+impl Sftp {
+    /// sftp://[user@]host[:port]/absolute/path, or sftp://host/~/path for one in the home directory
+    pub fn new(url: &str) -> Self {
+        let rest = url.strip_prefix("sftp://").expect("not an sftp url");
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (user.to_string(), host),
+            None => (
+                std::env::var("USER").expect("no user in sftp url and USER isn't set"),
+                authority,
+            ),
+        };
+        // [::1]:22 or host:22
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .unwrap_or_else(|_| panic!("bad port in sftp url {url}")),
+            ),
+            _ => (host, 22),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        assert!(!host.is_empty(), "no host in sftp url {url}");
+        let root = match path.strip_prefix('~') {
+            Some(home) => PathBuf::from(home.trim_start_matches('/')),
+            None => Path::new("/").join(path),
+        };
+        Sftp {
+            user,
+            host: host.to_string(),
+            port,
+            root,
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn path(&self, path: &Path) -> PathBuf {
+        let path = self.root.join(path);
+        if path.as_os_str().is_empty() {
+            // the home directory
+            PathBuf::from(".")
+        } else {
+            path
+        }
+    }
+
+    fn connect(&self) -> Result<ssh2::Sftp> {
+        debug!("connecting to {}@{}:{}", self.user, self.host, self.port);
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, format!("no address for {}", self.host))
+            })?;
+        let tcp = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(READ_TIMEOUT.as_millis() as u32);
+        session.handshake()?;
+        self.check_host_key(&session)?;
+
+        if std::env::var_os("SSH_AUTH_SOCK").is_some() {
+            if let Err(e) = session.userauth_agent(&self.user) {
+                debug!("ssh agent didn't get us in: {}", e);
+            }
+        }
+        let ssh_dir = ssh_dir()?;
+        for key in KEYS {
+            let key = ssh_dir.join(key);
+            if session.authenticated() {
+                break;
+            }
+            if key.exists() {
+                if let Err(e) = session.userauth_pubkey_file(&self.user, None, &key, None) {
+                    debug!("{:?} didn't get us in: {}", key, e);
+                }
+            }
+        }
+        if !session.authenticated() {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "{}@{} accepted no key from the ssh agent or {:?}",
+                    self.user, self.host, ssh_dir
+                ),
+            ));
+        }
+        Ok(session.sftp()?)
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result<()> {
+        let known_hosts_path = ssh_dir()?.join("known_hosts");
+        let mut known_hosts = session.known_hosts()?;
+        if known_hosts_path.exists() {
+            known_hosts.read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)?;
+        }
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| Error::other("server sent no host key"))?;
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "{} isn't in {:?}, ssh to it once to check and add its key",
+                    self.host, known_hosts_path
+                ),
+            )),
+            CheckResult::Mismatch => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "the host key of {} doesn't match {:?}, it may be an impostor",
+                    self.host, known_hosts_path
+                ),
+            )),
+            CheckResult::Failure => Err(Error::other(format!(
+                "cannot check the host key of {} against {:?}",
+                self.host, known_hosts_path
+            ))),
+        }
+    }
+
+    /// run f on the connection, connecting first if there isn't one, a connection that fails
+    /// (rather than the server saying no) is dropped and f is tried once more on a new one
+    fn call<T>(&self, f: impl Fn(&ssh2::Sftp) -> std::result::Result<T, ssh2::Error>) -> Result<T> {
+        let mut connection = self.connection.lock().expect("connection lock poisoned");
+        let mut fresh = false;
+        loop {
+            let sftp = match connection.as_ref() {
+                Some(sftp) => sftp.clone(),
+                None => {
+                    fresh = true;
+                    connection.insert(Arc::new(self.connect()?)).clone()
+                }
+            };
+            match f(&sftp) {
+                // negative codes are libssh2's own, like the socket failing
+                Err(e) if matches!(e.code(), ErrorCode::Session(_)) => {
+                    debug!("sftp connection to {} failed: {}", self.host, e);
+                    *connection = None;
+                    if fresh {
+                        return Err(e.into());
+                    }
+                }
+                ret => return ret.map_err(Error::from),
+            }
+        }
+    }
+}
+
+fn ssh_dir() -> Result<PathBuf> {
+    let home = std::env::var_os("HOME")
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "HOME isn't set, so neither is ~/.ssh"))?;
+    Ok(PathBuf::from(home).join(".ssh"))
+}
+
+fn stat(stat: &ssh2::FileStat) -> Option<Stat> {
+    let kind = match stat.file_type() {
+        ssh2::FileType::Directory => FileType::Directory,
+        ssh2::FileType::RegularFile => FileType::RegularFile,
+        ssh2::FileType::Symlink => FileType::Symlink,
+        ssh2::FileType::NamedPipe => FileType::NamedPipe,
+        ssh2::FileType::CharDevice => FileType::CharDevice,
+        ssh2::FileType::BlockDevice => FileType::BlockDevice,
+        ssh2::FileType::Socket => FileType::Socket,
+        ssh2::FileType::Other(_) => return None,
+    };
+    let size = stat.size.unwrap_or(0);
+    // SFTP only has these two times
+    let mtime = UNIX_EPOCH + Duration::from_secs(stat.mtime.unwrap_or(0));
+    let attr = FileAttr {
+        ino: 0,
+        size,
+        blocks: size.div_ceil(512),
+        atime: UNIX_EPOCH + Duration::from_secs(stat.atime.unwrap_or(0)),
+        mtime,
+        ctime: mtime,
+        crtime: mtime,
+        kind,
+        perm: stat.perm.unwrap_or(0) as u16,
+        nlink: 1,
+        uid: stat.uid.unwrap_or(0),
+        gid: stat.gid.unwrap_or(0),
+        rdev: 0,
+        blksize: 512,
+        flags: 0,
+    };
+    // SFTP doesn't tell hard links apart
+    Some(Stat {
+        attr,
+        link_id: None,
+    })
+}
+
+impl RemoteBackend for Sftp {
+    fn read_dir(&self, dir: &Path) -> Result<Vec<(OsString, Stat)>> {
+        let entries = self.call(|sftp| sftp.readdir(self.path(dir)))?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|(path, st)| Some((path.file_name()?.to_os_string(), stat(&st)?)))
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat> {
+        let st = self.call(|sftp| sftp.lstat(&self.path(path)))?;
+        stat(&st).ok_or_else(|| Error::from(ErrorKind::NotFound))
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        self.call(|sftp| sftp.readlink(&self.path(path)))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RemoteFile>> {
+        let path = self.path(path);
+        let file = self.call(|sftp| sftp.open(&path))?;
+        Ok(Box::new(SftpFile {
+            path,
+            file: Mutex::new(file),
+        }))
+    }
+
+    fn probe(&self) -> Result<()> {
+        // connects if it isn't
+        self.call(|sftp| sftp.lstat(&self.path(Path::new(""))))
+            .map(|_| ())
+    }
+}
+
+struct SftpFile {
+    path: PathBuf,
+    file: Mutex<ssh2::File>,
+}
+
+impl Debug for SftpFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SftpFile {{ path: {:?} }}", self.path)
+    }
+}
+
+impl RemoteFile for SftpFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let mut file = self.file.lock().expect("file lock poisoned");
+        file.seek(SeekFrom::Start(offset))?;
+        // each SFTP read is at most about 32K, fill as much as was asked for in one go
+        remote::fill(&mut *file, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(url: &str) -> (String, String, u16, PathBuf) {
+        let sftp = Sftp::new(url);
+        (sftp.user, sftp.host, sftp.port, sftp.root)
+    }
+
+    #[test]
+    fn urls() {
+        let parsed = |url| {
+            let (user, host, port, root) = parse(url);
+            (
+                user,
+                host,
+                port,
+                root.into_os_string().into_string().unwrap(),
+            )
+        };
+        let expect = |user: &str, host: &str, port, root: &str| {
+            (user.to_string(), host.to_string(), port, root.to_string())
+        };
+        assert_eq!(
+            parsed("sftp://alice@nas/srv/roms"),
+            expect("alice", "nas", 22, "/srv/roms")
+        );
+        assert_eq!(
+            parsed("sftp://alice@nas:2222/srv/roms/"),
+            expect("alice", "nas", 2222, "/srv/roms/")
+        );
+        assert_eq!(parsed("sftp://alice@nas"), expect("alice", "nas", 22, "/"));
+        assert_eq!(
+            parsed("sftp://alice@[::1]:2200/data"),
+            expect("alice", "::1", 2200, "/data")
+        );
+        assert_eq!(
+            parsed("sftp://alice@[fe80::1]/data"),
+            expect("alice", "fe80::1", 22, "/data")
+        );
+        // relative to the home directory
+        assert_eq!(
+            parsed("sftp://alice@nas/~/roms"),
+            expect("alice", "nas", 22, "roms")
+        );
+        assert_eq!(parsed("sftp://alice@nas/~"), expect("alice", "nas", 22, ""));
+    }
+
+    #[test]
+    fn default_user() {
+        // the same as ssh, unless USER isn't set, which is an error
+        if let Ok(user) = std::env::var("USER") {
+            assert_eq!(parse("sftp://nas:2222/x").0, user);
+        }
+    }
+
+    #[test]
+    fn paths() {
+        let home = Sftp::new("sftp://alice@nas/~");
+        assert_eq!(home.path(Path::new("")), Path::new("."));
+        assert_eq!(home.path(Path::new("gba/a.gba")), Path::new("gba/a.gba"));
+        let roms = Sftp::new("sftp://alice@nas/~/roms");
+        assert_eq!(roms.path(Path::new("")), Path::new("roms"));
+        assert_eq!(roms.path(Path::new("gba")), Path::new("roms/gba"));
+        let absolute = Sftp::new("sftp://alice@nas/srv");
+        assert_eq!(absolute.path(Path::new("")), Path::new("/srv"));
+        assert_eq!(absolute.path(Path::new("gba")), Path::new("/srv/gba"));
+    }
+
+    #[test]
+    #[should_panic(expected = "bad port")]
+    fn bad_port() {
+        Sftp::new("sftp://alice@nas:ssh/srv");
+    }
+
+    #[test]
+    #[should_panic(expected = "no host")]
+    fn no_host() {
+        Sftp::new("sftp://alice@/srv");
+    }
+
+    /// against a real server, with CACHE_FS_SFTP_TEST set to a url like sftp://localhost/tmp/dir
+    /// for an sshd on this machine that lets us in, the directory is filled first locally
+    #[test]
+    #[ignore]
+    fn local_sshd() {
+        let url = match std::env::var("CACHE_FS_SFTP_TEST") {
+            Ok(url) => url,
+            Err(_) => return eprintln!("CACHE_FS_SFTP_TEST isn't set, skipping"),
+        };
+        let sftp = Sftp::new(&url);
+        let dir = &sftp.root;
+        assert!(
+            dir.is_absolute(),
+            "CACHE_FS_SFTP_TEST needs an absolute path"
+        );
+        std::fs::remove_dir_all(dir).ok();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let big: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("big"), &big).unwrap();
+        std::fs::write(dir.join("sub/small"), b"hello").unwrap();
+        std::os::unix::fs::symlink("sub/small", dir.join("link")).unwrap();
+
+        sftp.probe().unwrap();
+        let mut names: Vec<_> = sftp
+            .read_dir(Path::new(""))
+            .unwrap()
+            .into_iter()
+            .map(|(name, stat)| (name.into_string().unwrap(), stat.attr.kind, stat.attr.size))
+            .filter(|(name, ..)| name != "." && name != "..")
+            .collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            names[0],
+            ("big".to_string(), FileType::RegularFile, 200_000)
+        );
+        assert_eq!(names[1].1, FileType::Symlink);
+        assert_eq!(names[2].1, FileType::Directory);
+        assert_eq!(
+            sftp.read_link(Path::new("link")).unwrap(),
+            Path::new("sub/small")
+        );
+        assert_eq!(sftp.stat(Path::new("sub/small")).unwrap().attr.size, 5);
+        assert!(sftp.stat(Path::new("missing")).is_err());
+
+        let file = sftp.open(Path::new("big")).unwrap();
+        // more than one SFTP read's worth
+        let mut buf = vec![0; 100_000];
+        assert_eq!(file.read_at(&mut buf, 50_000).unwrap(), 100_000);
+        assert_eq!(buf, big[50_000..150_000]);
+        assert_eq!(file.read_at(&mut buf, 150_000).unwrap(), 50_000);
+        assert_eq!(buf[..50_000], big[150_000..]);
+        assert_eq!(file.read_at(&mut buf, 200_000).unwrap(), 0);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{
+    chunk::{self, ContentHash},
+    compress,
+};
+use log::debug;
+use std::{
+    fs::File,
+    io::{ErrorKind, Result},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+pub const OBJECTS_DIR: &str = "objects";
+
+/// complete cached files by the hash of their contents, under objects/<first byte>/<hash> in hex,
+/// every cached path with the same contents is a hard link to the same object so identical files
+/// take up space once, and a file that moved on the remote is linked back in rather than fetched
+///
+/// an object nothing under the cache root links to anymore is kept until the cache needs room
+#[derive(Clone, Debug)]
+pub struct Store {
+    dir: PathBuf,
+    tmp_dir: PathBuf,
+}
+
+impl Store {
+    /// the store of cache_dir if it has one, created first if create, once a cache dir has a store
+    /// everything caching into it uses it
+    pub fn open(cache_dir: &Path, create: bool) -> Result<Option<Self>> {
+        let dir = cache_dir.join(OBJECTS_DIR);
+        if create {
+            std::fs::create_dir_all(&dir)?;
+        }
+        let tmp_dir = cache_dir.join(chunk::TMP_DIR);
+        Ok(dir.is_dir().then_some(Store { dir, tmp_dir }))
+    }
+
+    fn object(&self, hash: &ContentHash) -> PathBuf {
+        let hex = blake3::Hash::from(*hash).to_hex();
+        self.dir.join(&hex[..2]).join(hex.as_str())
+    }
+
+    /// link the object with hash to path if there is one, returns whether there was
+    pub fn link(&self, hash: &ContentHash, path: &Path) -> Result<bool> {
+        let object = self.object(hash);
+        if !object.exists() {
+            return Ok(false);
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        match std::fs::hard_link(&object, path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// add the complete cached file at path with hash, if the same contents are stored already
+    /// path is replaced with a link to them instead
+    pub fn add(&self, hash: &ContentHash, path: &Path) -> Result<()> {
+        let object = self.object(hash);
+        if let Some(parent) = object.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let stored = match std::fs::metadata(&object) {
+            Ok(stored) => stored,
+            Err(e) if e.kind() == ErrorKind::NotFound => return std::fs::hard_link(path, &object),
+            Err(e) => return Err(e),
+        };
+        let ours = File::open(path)?;
+        let meta = ours.metadata()?;
+        if stored.ino() == meta.ino() && stored.dev() == meta.dev() {
+            return Ok(());
+        }
+        // a copy we just fetched is better than a stored one that's obviously wrong, either could
+        // be compressed
+        let replace = compress::plain_size(&File::open(&object)?)? != compress::plain_size(&ours)?;
+        let (from, to) = if replace {
+            (path, object.as_path())
+        } else {
+            (object.as_path(), path)
+        };
+        debug!(
+            "{:?} is stored already, linking {:?} to {:?}",
+            path, from, to
+        );
+        let tmp = chunk::tmp_path(&self.tmp_dir)?;
+        std::fs::hard_link(from, &tmp)?;
+        std::fs::rename(tmp, to)
+    }
+
+    /// drop the object with hash, for when it turned out to be corrupt
+    pub fn remove(&self, hash: &ContentHash) -> Result<()> {
+        match std::fs::remove_file(self.object(hash)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// every object nothing under the cache root links to, with its hash and size, temporary links
+    /// left by a crash have no hash
+    pub fn unlinked(&self) -> Vec<(Option<ContentHash>, PathBuf, u64)> {
+        let mut unlinked = Vec::new();
+        for dir in std::fs::read_dir(&self.dir).into_iter().flatten().flatten() {
+            for de in std::fs::read_dir(dir.path())
+                .into_iter()
+                .flatten()
+                .flatten()
+            {
+                let meta = match de.metadata() {
+                    Ok(meta) if meta.is_file() && meta.nlink() == 1 => meta,
+                    _ => continue,
+                };
+                let hash = de
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| blake3::Hash::from_hex(name).ok())
+                    .map(|hash| *hash.as_bytes());
+                unlinked.push((hash, de.path(), meta.len()));
+            }
+        }
+        unlinked
+    }
+
+    /// delete every object nothing links to anymore, returns how many bytes that freed
+    pub fn prune(&self) -> u64 {
+        let mut freed = 0;
+        for (_, object, size) in self.unlinked() {
+            debug!("pruning {:?}", object);
+            if std::fs::remove_file(&object).is_ok() {
+                freed += size;
+            }
+        }
+        freed
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{
+    chunk,
+    evict::Evictor,
+    index, pin, remote,
+    store::Store,
+    warm::{self, Progress},
+    FileTree,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+/// `cache-fs verify [-o remote_dir=/remote/dir,dry_run] /local/cache/dir [PATH...]` checks every
+/// cached file, or only those under PATHs relative to the remote root, against the index and
+/// deletes corrupt copies and anything cached that isn't in the index anymore, with remote_dir
+/// corrupt files are fetched again, dry_run only reports
+///
+/// exits 1 if anything was wrong
+pub fn command(mut args: impl Iterator<Item = OsString>) {
+    let mut remote_dir = None;
+    let mut dry_run = false;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg != "-o" {
+            paths.push(PathBuf::from(arg));
+            continue;
+        }
+        let opts = args.next().expect("found -o but missing opts");
+        for opt in opts.to_str().expect("non-utf8 opts").split(',') {
+            if let Some(dir) = opt.strip_prefix("remote_dir=") {
+                remote_dir = Some(PathBuf::from(dir));
+            } else if opt == "dry_run" {
+                dry_run = true;
+            } else {
+                panic!("unknown verify option {opt}");
+            }
+        }
+    }
+    let mut paths = paths.into_iter();
+    let cache_dir = paths.next().expect("missing cache_dir");
+    let root = cache_dir.join("root");
+    let chunks_dir = cache_dir.join(chunk::CHUNKS_DIR);
+    let store = Store::open(&cache_dir, false).expect("cannot open the store");
+
+    let index_path = cache_dir.join(index::INDEX_NAME);
+    let tree = FileTree::load(&index_path, remote_dir.as_deref())
+        .unwrap_or_else(|e| panic!("cannot load {:?}: {e}", index_path));
+    let mut files = BTreeMap::new();
+    let paths: Vec<_> = paths.collect();
+    // everything, which is also when anything cached that isn't in it can be found
+    let everything = paths.is_empty();
+    for path in paths
+        .iter()
+        .map(|p| p.as_path())
+        .chain(everything.then_some(Path::new("")))
+    {
+        let path = pin::normalize(path);
+        match tree.resolve(&path) {
+            Some(ino) => files.extend(
+                tree.files_under(ino)
+                    .into_iter()
+                    .map(|(ino, path, attr, hash)| (ino, (path, attr, hash))),
+            ),
+            None => eprintln!("verify: {:?} isn't in the index", path),
+        }
+    }
+
+    let (mut checked, mut failed) = (0, 0);
+    let mut corrupt = BTreeMap::new();
+    for (ino, (path, attr, hash)) in &files {
+        if !root.join(path).exists() {
+            continue;
+        }
+        checked += 1;
+        match chunk::verify(&root, &chunks_dir, path, attr, hash.as_ref(), !dry_run) {
+            Ok(None) => (),
+            Ok(Some(problem)) => {
+                println!("corrupt /{}: {problem}", path.display());
+                corrupt.insert(*ino, (path.clone(), *attr, *hash));
+            }
+            Err(e) => {
+                println!("cannot verify /{}: {e}", path.display());
+                failed += 1;
+            }
+        }
+    }
+
+    let mut orphans = Vec::new();
+    if everything {
+        let cached: HashSet<_> = files.values().map(|(path, ..)| path.as_path()).collect();
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            for de in std::fs::read_dir(root.join(&dir))
+                .into_iter()
+                .flatten()
+                .flatten()
+            {
+                let path = dir.join(de.file_name());
+                if de.file_type().is_ok_and(|t| t.is_dir()) {
+                    dirs.push(path);
+                } else if !cached.contains(path.as_path()) {
+                    println!("not in the index /{}", path.display());
+                    orphans.push(path);
+                }
+            }
+        }
+    }
+    // stored contents no file in the index has, ones that some file does are kept even if nothing
+    // links to them right now, in case it's opened
+    let mut unused = Vec::new();
+    if let Some(store) = store.as_ref().filter(|_| everything) {
+        let hashes: HashSet<_> = files.values().filter_map(|(_, _, hash)| *hash).collect();
+        for (hash, object, _) in store.unlinked() {
+            if hash.is_none_or(|hash| !hashes.contains(&hash)) {
+                println!("not in the index {}", object.display());
+                unused.push(object);
+            }
+        }
+    }
+
+    if !dry_run && (!corrupt.is_empty() || !orphans.is_empty() || !unused.is_empty()) {
+        for path in corrupt.values().map(|(path, ..)| path).chain(&orphans) {
+            if let Err(e) = chunk::discard(&root, &chunks_dir, path) {
+                println!("cannot delete /{}: {e}", path.display());
+                failed += 1;
+            }
+        }
+        if let Some(store) = &store {
+            // corrupt files are most likely links to a corrupt stored copy
+            for hash in corrupt.values().filter_map(|(_, _, hash)| hash.as_ref()) {
+                if let Err(e) = store.remove(hash) {
+                    println!("cannot delete stored copy: {e}");
+                    failed += 1;
+                }
+            }
+        }
+        for object in &unused {
+            if let Err(e) = std::fs::remove_file(object) {
+                println!("cannot delete {}: {e}", object.display());
+                failed += 1;
+            }
+        }
+        // loading it drops whatever is gone from the cache now
+        if let Err(e) = Evictor::load(&cache_dir, None).save() {
+            eprintln!("verify: cannot save cache access times: {:?}", e);
+        }
+    }
+
+    eprintln!(
+        "verify: checked {checked} cached files, {} corrupt, {} not in the index{}",
+        corrupt.len(),
+        orphans.len() + unused.len(),
+        if dry_run { ", nothing deleted" } else { "" }
+    );
+    let ok = corrupt.is_empty() && orphans.is_empty() && unused.is_empty() && failed == 0;
+    if let Some(remote_dir) = remote_dir.filter(|_| !dry_run && !corrupt.is_empty()) {
+        let mut progress = Progress::new(None);
+        let remote = remote::open(&remote_dir);
+        let cache = warm::Cache {
+            dir: &cache_dir,
+            compress: None,
+            max_cache_size: None,
+        };
+        warm::fetch(remote, &cache, corrupt, &mut progress);
+        progress.finish();
+    }
+    if !ok {
+        std::process::exit(1);
+    }
+}
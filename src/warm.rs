@@ -0,0 +1,401 @@
+use crate::{
+    chunk::{self, ContentHash, Downloads},
+    compress,
+    evict::{self, Evictor},
+    health::{self, Health},
+    pin,
+    remote::{self, RemoteBackend},
+    store::Store,
+    FileTree,
+};
+use fuser::FileAttr;
+use std::{
+    collections::BTreeMap,
+    ffi::{CString, OsString},
+    io::{ErrorKind, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// how often the progress line is redrawn
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+// read size when warming through a mount, a chunk so the bandwidth limit is applied smoothly
+const READ_SIZE: usize = chunk::CHUNK_SIZE as usize;
+
+/// `cache-fs warm [-o remote_dir=/remote/dir,bwlimit=10M,compress,max_cache_size=20G]
+/// /local/cache/dir PATTERN...` fills the cache directly, patterns are relative to the remote root,
+/// max_cache_size evicts like the mount does to make room
+///
+/// `cache-fs warm [-o bwlimit=10M] PATTERN...` without remote_dir reads paths in a mounted cache-fs
+/// instead, so the running mount caches them
+///
+/// patterns can be files, whole directories or globs in any component, anything already cached is
+/// skipped so an interrupted warm picks up where it left off
+pub fn command(mut args: impl Iterator<Item = OsString>) {
+    let mut remote_dir = None;
+    let mut bwlimit = None;
+    let mut compress = None;
+    let mut max_cache_size = None;
+    let mut patterns = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg != "-o" {
+            patterns.push(arg);
+            continue;
+        }
+        let opts = args.next().expect("found -o but missing opts");
+        for opt in opts.to_str().expect("non-utf8 opts").split(',') {
+            if let Some(dir) = opt.strip_prefix("remote_dir=") {
+                remote_dir = Some(PathBuf::from(dir));
+            } else if let Some(limit) = opt.strip_prefix("bwlimit=") {
+                let limit = evict::parse_size(limit)
+                    .expect("bwlimit= must be a size per second like 512K or 10M");
+                bwlimit = Some(limit);
+            } else if let Some(size) = opt.strip_prefix("max_cache_size=") {
+                let size = evict::parse_size(size)
+                    .expect("max_cache_size= must be a size like 1024, 512M or 20G");
+                max_cache_size = Some(size);
+            } else if opt == "compress" {
+                compress = Some(compress::DEFAULT_LEVEL);
+            } else if let Some(level) = opt.strip_prefix("compress=") {
+                compress = Some(
+                    level
+                        .parse()
+                        .expect("compress= must be a zstd level like 3"),
+                );
+            } else {
+                panic!("unknown warm option {opt}");
+            }
+        }
+    }
+
+    let mut progress = Progress::new(bwlimit);
+    match remote_dir {
+        Some(remote_dir) => {
+            let mut patterns = patterns.into_iter();
+            let cache_dir = PathBuf::from(patterns.next().expect("missing cache_dir"));
+            let cache = Cache {
+                dir: &cache_dir,
+                compress,
+                max_cache_size,
+            };
+            warm_cache(remote_dir, &cache, patterns, &mut progress);
+        }
+        None => warm_mounted(patterns, &mut progress),
+    }
+    if !progress.finish() {
+        std::process::exit(1);
+    }
+}
+
+/// a cache dir to fetch into and the mount options that change how
+pub struct Cache<'a> {
+    pub dir: &'a Path,
+    // zstd level to compress complete files with
+    pub compress: Option<i32>,
+    pub max_cache_size: Option<u64>,
+}
+
+/// fetch straight from the remote into the cache, the same way opening the files would
+fn warm_cache(
+    remote_dir: PathBuf,
+    cache: &Cache,
+    patterns: impl Iterator<Item = OsString>,
+    progress: &mut Progress,
+) {
+    let remote = remote::open(&remote_dir);
+    let tree = FileTree::load_or_build(remote.as_ref(), &remote_dir, cache.dir)
+        .unwrap_or_else(|e| panic!("could not load file tree: {e}"));
+
+    // by inode so overlapping patterns only fetch a file once
+    let mut files = BTreeMap::new();
+    for pattern in patterns {
+        let pattern = pin::normalize(Path::new(&pattern));
+        let paths = expand(&pattern, |dir| {
+            let children = tree.folder(tree.resolve(dir)?)?;
+            Some(children.keys().cloned().collect())
+        });
+        let inos: Vec<_> = paths.iter().filter_map(|p| tree.resolve(p)).collect();
+        if inos.is_empty() {
+            eprintln!("warm: nothing matches {:?}", pattern);
+        }
+        for ino in inos {
+            for (ino, path, attr, hash) in tree.files_under(ino) {
+                files.insert(ino, (path, attr, hash));
+            }
+        }
+    }
+
+    fetch(remote, cache, files, progress);
+}
+
+/// fetch files by inode straight from the remote into the cache, skipping any already cached
+pub fn fetch(
+    remote: Arc<dyn RemoteBackend>,
+    cache: &Cache,
+    files: BTreeMap<u64, (PathBuf, FileAttr, Option<ContentHash>)>,
+    progress: &mut Progress,
+) {
+    let cache_dir = cache.dir;
+    let evictor = Evictor::load(cache_dir, cache.max_cache_size);
+    let mut downloads = Downloads::new(
+        remote.clone(),
+        cache_dir.join("root"),
+        cache_dir.join(chunk::CHUNKS_DIR),
+        cache_dir.join(chunk::TMP_DIR),
+        Arc::new(Mutex::new(evictor)),
+        Arc::new(Health::new(remote, health::DEFAULT_TIMEOUT)),
+        Store::open(cache_dir, false).unwrap_or_default(),
+    );
+    if let Some(level) = cache.compress {
+        downloads.compress(level);
+    }
+
+    progress.start(
+        files.len(),
+        files.values().map(|(_, attr, _)| attr.size).sum(),
+    );
+    for (ino, (path, attr, hash)) in files {
+        if downloads.is_complete(&path, hash.as_ref()) {
+            progress.skipped(attr.size);
+            progress.file_done();
+            continue;
+        }
+        let file = match downloads.open(ino, &path, &attr, hash, 0) {
+            Ok(file) => file,
+            Err(e) => {
+                progress.failed(&path, e);
+                continue;
+            }
+        };
+        let mut file = file.lock().expect("partial file lock poisoned");
+        // chunks left over from an interrupted warm or earlier reads
+        progress.skipped(file.cached_bytes());
+        loop {
+            let before = file.cached_bytes();
+            match file.fetch_next(1) {
+                Ok(more) => {
+                    progress.fetched(file.cached_bytes() - before);
+                    if !more {
+                        progress.file_done();
+                        break;
+                    }
+                }
+                Err(e) => {
+                    progress.failed(&path, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    downloads.finish();
+    let saved = downloads.evictor().save();
+    if let Err(e) = saved {
+        eprintln!("warm: cannot save cache access times: {:?}", e);
+    }
+}
+
+/// read every file through a mounted cache-fs, which caches them as a side effect
+fn warm_mounted(patterns: Vec<OsString>, progress: &mut Progress) {
+    let mut files = BTreeMap::new();
+    for pattern in patterns {
+        let paths = expand(Path::new(&pattern), |dir| {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            let read_dir = std::fs::read_dir(dir).ok()?;
+            Some(read_dir.flatten().map(|de| de.file_name()).collect())
+        });
+        let mut found = false;
+        let mut dirs = Vec::new();
+        for path in paths {
+            match std::fs::symlink_metadata(&path) {
+                Ok(meta) if meta.is_dir() => dirs.push(path),
+                Ok(meta) if meta.is_file() => {
+                    files.insert(path, meta.len());
+                }
+                _ => continue,
+            }
+            found = true;
+        }
+        while let Some(dir) = dirs.pop() {
+            for de in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+                match de.metadata() {
+                    Ok(meta) if meta.is_dir() => dirs.push(de.path()),
+                    Ok(meta) if meta.is_file() => {
+                        files.insert(de.path(), meta.len());
+                    }
+                    _ => (),
+                }
+            }
+        }
+        if !found {
+            eprintln!("warm: nothing matches {:?}", pattern);
+        }
+    }
+
+    progress.start(files.len(), files.values().sum());
+    let mut buf = vec![0; READ_SIZE];
+    for (path, size) in files {
+        if is_cached(&path) {
+            progress.skipped(size);
+            progress.file_done();
+            continue;
+        }
+        let ret = std::fs::File::open(&path).and_then(|mut file| loop {
+            match file.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => progress.fetched(n as u64),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        });
+        match ret {
+            Ok(_) => progress.file_done(),
+            Err(e) => progress.failed(&path, e),
+        }
+    }
+}
+
+/// true if user.cachefs.cached says path is fully cached
+fn is_cached(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut value = [0u8; 8];
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            c"user.cachefs.cached".as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    len >= 0 && &value[..len as usize] == b"full"
+}
+
+/// every path matching pattern, where any component can be a glob, children lists a directory or
+/// returns None if it isn't one, components without globs aren't checked to exist
+fn expand(pattern: &Path, children: impl Fn(&Path) -> Option<Vec<OsString>>) -> Vec<PathBuf> {
+    let mut matched = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let name = component.as_os_str();
+        let glob = name
+            .to_str()
+            .filter(|name| name.contains(['*', '?', '[']))
+            .and_then(|name| glob::Pattern::new(name).ok());
+        matched = match glob {
+            None => matched.into_iter().map(|path| path.join(name)).collect(),
+            Some(glob) => {
+                let mut next = Vec::new();
+                for dir in matched {
+                    for child in children(&dir).unwrap_or_default() {
+                        if child.to_str().is_some_and(|child| glob.matches(child)) {
+                            next.push(dir.join(child));
+                        }
+                    }
+                }
+                next
+            }
+        };
+    }
+    matched
+}
+
+/// progress line on stderr and the bandwidth limit
+pub struct Progress {
+    bwlimit: Option<u64>,
+    start: Instant,
+    last_report: Instant,
+    total_files: usize,
+    total_bytes: u64,
+    files: usize,
+    failed: usize,
+    // already cached, counts towards done but not the transfer rate
+    skipped: u64,
+    fetched: u64,
+}
+
+impl Progress {
+    pub fn new(bwlimit: Option<u64>) -> Self {
+        Progress {
+            bwlimit,
+            start: Instant::now(),
+            last_report: Instant::now(),
+            total_files: 0,
+            total_bytes: 0,
+            files: 0,
+            failed: 0,
+            skipped: 0,
+            fetched: 0,
+        }
+    }
+
+    fn start(&mut self, files: usize, bytes: u64) {
+        self.total_files = files;
+        self.total_bytes = bytes;
+        self.start = Instant::now();
+        self.report();
+    }
+
+    fn skipped(&mut self, bytes: u64) {
+        self.skipped += bytes;
+    }
+
+    fn fetched(&mut self, bytes: u64) {
+        self.fetched += bytes;
+        if let Some(bwlimit) = self.bwlimit {
+            let due = Duration::from_secs_f64(self.fetched as f64 / bwlimit as f64);
+            if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        if self.last_report.elapsed() >= REPORT_INTERVAL {
+            self.report();
+        }
+    }
+
+    fn file_done(&mut self) {
+        self.files += 1;
+        if self.last_report.elapsed() >= REPORT_INTERVAL {
+            self.report();
+        }
+    }
+
+    fn failed(&mut self, path: &Path, e: std::io::Error) {
+        self.failed += 1;
+        eprintln!("\nwarm: cannot fetch {:?}: {}", path, e);
+    }
+
+    fn report(&mut self) {
+        let rate = self.fetched as f64 / self.start.elapsed().as_secs_f64().max(0.001);
+        eprint!(
+            "\rwarm: {}/{} files, {}/{}, {}/s   ",
+            self.files,
+            self.total_files,
+            evict::human(self.skipped + self.fetched),
+            evict::human(self.total_bytes),
+            evict::human(rate as u64)
+        );
+        std::io::stderr().flush().ok();
+        self.last_report = Instant::now();
+    }
+
+    /// print the final line, returns false if anything failed
+    pub fn finish(&mut self) -> bool {
+        self.report();
+        eprintln!();
+        if self.failed > 0 {
+            eprintln!(
+                "warm: {} files failed, run again to retry them",
+                self.failed
+            );
+        }
+        self.failed == 0
+    }
+}